@@ -0,0 +1,277 @@
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use rocket::serde::json::{from_str, serde_json::to_string};
+use std::io::{BufRead, Read, Write};
+
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::transaction::read;
+
+/// First bytes of a binary export.
+pub const MAGIC: [u8; 4] = *b"FBCX";
+pub const FORMAT_VERSION: u16 = 1;
+/// Larger records are taken for a corrupt length.
+pub const MAX_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One JSON block per line.
+    Jsonl,
+    /// A header, then each block prefixed by its length.
+    Binary,
+}
+
+/// Start of a binary export: `MAGIC`, the version as a u16, the genesis
+/// hash of the chain and how many blocks follow as a u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u16,
+    pub genesis: BlockHash,
+    pub count: u64,
+}
+
+impl ArchiveHeader {
+    pub fn new(genesis: BlockHash, count: u64) -> Self {
+        ArchiveHeader {
+            version: FORMAT_VERSION,
+            genesis,
+            count,
+        }
+    }
+
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.version.to_be_bytes())?;
+        writer.write_all(&self.genesis)?;
+        writer.write_all(&self.count.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        let mut bytes = [0; 46];
+        reader.read_exact(&mut bytes)?;
+        let at = &mut 0;
+
+        if read::<4>(&bytes, at) != Some(MAGIC) {
+            bail!("not a binary chain export");
+        }
+        let version = u16::from_be_bytes(read(&bytes, at).unwrap());
+        if version != FORMAT_VERSION {
+            bail!("unsupported export format version {}", version);
+        }
+
+        Ok(ArchiveHeader {
+            version,
+            genesis: read(&bytes, at).unwrap(),
+            count: u64::from_be_bytes(read(&bytes, at).unwrap()),
+        })
+    }
+}
+
+/// A block as its timestamp and block number as u64s, difficulty and
+/// nonce as u32s, hash and previous hash, then its data.
+pub fn encode_block(block: &Block) -> Vec<u8> {
+    let mut bytes = block.timestamp.to_be_bytes().to_vec();
+    bytes.extend((block.block_number as u64).to_be_bytes());
+    bytes.extend(block.difficulty.to_be_bytes());
+    bytes.extend(block.nonce.to_be_bytes());
+    bytes.extend(block.hash);
+    bytes.extend(block.prev_hash);
+    bytes.extend(&block.data);
+    bytes
+}
+
+pub fn decode_block(bytes: &[u8]) -> Option<Block> {
+    let mut at = 0;
+
+    Some(Block {
+        timestamp: u64::from_be_bytes(read(bytes, &mut at)?),
+        block_number: u64::from_be_bytes(read(bytes, &mut at)?).try_into().ok()?,
+        difficulty: u32::from_be_bytes(read(bytes, &mut at)?),
+        nonce: u32::from_be_bytes(read(bytes, &mut at)?),
+        hash: read(bytes, &mut at)?,
+        prev_hash: read(bytes, &mut at)?,
+        data: bytes[at..].to_vec(),
+    })
+}
+
+/// Writes blocks in `format`, the binary header first.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    format: Format,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, format: Format, header: ArchiveHeader) -> Result<Self> {
+        if format == Format::Binary {
+            header.write(&mut writer)?;
+        }
+
+        Ok(ArchiveWriter { writer, format })
+    }
+
+    pub fn write(&mut self, block: &Block) -> Result<()> {
+        match self.format {
+            Format::Jsonl => writeln!(self.writer, "{}", to_string(block)?)?,
+            Format::Binary => {
+                let bytes = encode_block(block);
+                self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&bytes)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the blocks of an export in file order, telling the format from
+/// its first bytes. A binary export ending before its header count is an
+/// error.
+pub struct ArchiveReader<R: BufRead> {
+    reader: R,
+    header: Option<ArchiveHeader>,
+    /// Blocks read so far.
+    read: u64,
+    /// Set at the end or on the first error.
+    done: bool,
+}
+
+impl<R: BufRead> ArchiveReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let header = match reader.fill_buf()?.starts_with(&MAGIC) {
+            true => Some(ArchiveHeader::read(&mut reader)?),
+            false => None,
+        };
+
+        Ok(ArchiveReader {
+            reader,
+            header,
+            read: 0,
+            done: false,
+        })
+    }
+
+    /// The binary header, `None` for JSON Lines.
+    pub fn header(&self) -> Option<ArchiveHeader> {
+        self.header
+    }
+
+    fn next_line(&mut self) -> Result<Option<Block>> {
+        let mut line = String::new();
+        while line.trim().is_empty() {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(from_str(&line)?))
+    }
+
+    fn next_record(&mut self, count: u64) -> Result<Option<Block>> {
+        if self.read == count {
+            return Ok(None);
+        }
+
+        let mut len = [0; 4];
+        self.reader
+            .read_exact(&mut len)
+            .map_err(|_| truncated(count, self.read))?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_RECORD_LEN {
+            bail!("record of {} bytes is too large", len);
+        }
+
+        let mut bytes = vec![0; len as usize];
+        self.reader
+            .read_exact(&mut bytes)
+            .map_err(|_| truncated(count, self.read))?;
+
+        decode_block(&bytes)
+            .map(Some)
+            .ok_or_else(|| anyhow!("record {} is not a block", self.read))
+    }
+}
+
+fn truncated(count: u64, read: u64) -> anyhow::Error {
+    anyhow!("export ends after {} of {} blocks", read, count)
+}
+
+impl<R: BufRead> Iterator for ArchiveReader<R> {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let block = match self.header {
+            Some(header) => self.next_record(header.count),
+            None => self.next_line(),
+        };
+        match block {
+            Ok(Some(_)) => self.read += 1,
+            _ => self.done = true,
+        }
+
+        block.transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::archive::*;
+    use crate::blockchain::transaction::{encode_transactions, Transaction};
+
+    fn blocks() -> Vec<Block> {
+        let mut block = Block::default();
+        block.data = encode_transactions(&[Transaction::new(7, b"tx".to_vec())]);
+        vec![Block::genesis(), block]
+    }
+
+    fn roundtrip(format: Format) -> Result<(Option<ArchiveHeader>, Vec<Block>)> {
+        let header = ArchiveHeader::new(Block::genesis().get_hash(), 2);
+        let mut writer = ArchiveWriter::new(vec![], format, header)?;
+        for block in blocks() {
+            writer.write(&block)?;
+        }
+        let bytes = writer.finish()?;
+
+        let reader = ArchiveReader::new(&bytes[..])?;
+        let header = reader.header();
+        Ok((header, reader.collect::<Result<_>>()?))
+    }
+
+    #[test]
+    fn roundtrip_test() -> Result<()> {
+        assert_eq!(roundtrip(Format::Jsonl)?, (None, blocks()));
+
+        let (header, read) = roundtrip(Format::Binary)?;
+        assert_eq!(read, blocks());
+        assert_eq!(header.unwrap().count, 2);
+        assert_eq!(header.unwrap().genesis, Block::genesis().get_hash());
+        Ok(())
+    }
+
+    #[test]
+    fn broken_binary_test() -> Result<()> {
+        let header = ArchiveHeader::new(Block::genesis().get_hash(), 2);
+        let mut writer = ArchiveWriter::new(vec![], Format::Binary, header)?;
+        writer.write(&Block::genesis())?;
+        let bytes = writer.finish()?;
+
+        let read: Vec<_> = ArchiveReader::new(&bytes[..])?.collect();
+        assert_eq!(read.len(), 2);
+        assert!(read[1].as_ref().unwrap_err().to_string().contains("1 of 2"));
+
+        let mut newer = bytes.clone();
+        newer[5] = 2;
+        assert!(ArchiveReader::new(&newer[..]).is_err());
+        assert!(decode_block(&encode_block(&Block::genesis())[..40]).is_none());
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
+use hex::encode;
 use rocket::serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fmt;
 
+use crate::blockchain::transaction::{decode_transactions, Transaction};
+
 pub type BlockHash = [u8; 32];
 
 #[allow(dead_code)]
@@ -17,6 +20,57 @@ pub struct Block {
     pub prev_hash: BlockHash,
 }
 
+/// Everything in a block except its data, which it commits to through
+/// `data_root`, enough to check proof of work and linkage before the body
+/// is downloaded.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BlockHeader {
+    pub timestamp: u64,
+    pub difficulty: u32,
+    pub block_number: usize,
+    pub nonce: u32,
+    /// Hash of the block data.
+    pub data_root: BlockHash,
+    pub hash: BlockHash,
+    pub prev_hash: BlockHash,
+}
+
+impl fmt::Debug for BlockHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockHeader")
+            .field("block_number", &self.block_number)
+            .field("difficulty", &self.difficulty)
+            .field("hash", &encode(self.hash))
+            .field("prev_hash", &encode(self.prev_hash))
+            .finish()
+    }
+}
+
+impl BlockHeader {
+    /// Hash of the header fields, which is the block hash.
+    pub fn calculate_hash(&self) -> BlockHash {
+        Block::header_hash(
+            self.timestamp,
+            &self.data_root,
+            &self.prev_hash,
+            self.difficulty,
+            self.nonce,
+        )
+    }
+
+    /// Whether the header hashes to its hash and that satisfies the
+    /// difficulty it claims.
+    pub fn has_valid_pow(&self) -> bool {
+        self.calculate_hash() == self.hash && Block::meets_difficulty(&self.hash, self.difficulty)
+    }
+
+    /// Expected number of hashes needed to find this header.
+    pub fn work(&self) -> u128 {
+        1u128 << self.difficulty.min(127)
+    }
+}
+
 impl fmt::Debug for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Block")
@@ -32,6 +86,7 @@ impl fmt::Debug for Block {
 
 #[allow(dead_code)]
 impl Block {
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Block {
         Block {
             timestamp: 0,
@@ -44,6 +99,21 @@ impl Block {
         }
     }
 
+    /// First block of every chain, the same on all nodes.
+    pub fn genesis() -> Block {
+        let data = b"genesis".to_vec();
+
+        Block {
+            timestamp: 0,
+            difficulty: 0,
+            nonce: 0,
+            block_number: 0,
+            hash: Block::calculate_hash(0, &data, &[0; 32], 0, 0),
+            data,
+            prev_hash: [0; 32],
+        }
+    }
+
     pub fn get_timestamp(&self) -> u64 {
         self.timestamp
     }
@@ -72,7 +142,91 @@ impl Block {
         self.block_number
     }
 
-    pub fn block_hash(bytes: &Vec<u8>) -> BlockHash {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            timestamp: self.timestamp,
+            difficulty: self.difficulty,
+            block_number: self.block_number,
+            nonce: self.nonce,
+            data_root: Block::data_root(&self.data),
+            hash: self.hash,
+            prev_hash: self.prev_hash,
+        }
+    }
+
+    /// The transactions carried in the block data, if it holds any.
+    pub fn transactions(&self) -> Option<Vec<Transaction>> {
+        decode_transactions(&self.data)
+    }
+
+    /// Hash a block from its parts, as done when mining and validating.
+    pub fn calculate_hash(
+        timestamp: u64,
+        data: &[u8],
+        prev_hash: &BlockHash,
+        difficulty: u32,
+        nonce: u32,
+    ) -> BlockHash {
+        Block::header_hash(
+            timestamp,
+            &Block::data_root(data),
+            prev_hash,
+            difficulty,
+            nonce,
+        )
+    }
+
+    /// What the header commits to of the block data.
+    pub fn data_root(data: &[u8]) -> BlockHash {
+        Block::block_hash(data)
+    }
+
+    /// Hash a block from its header fields, the data through its root.
+    pub fn header_hash(
+        timestamp: u64,
+        data_root: &BlockHash,
+        prev_hash: &BlockHash,
+        difficulty: u32,
+        nonce: u32,
+    ) -> BlockHash {
+        let block_hash_data = [
+            &timestamp.to_be_bytes(),
+            &data_root[..],
+            &prev_hash[..],
+            &difficulty.to_be_bytes(),
+            &nonce.to_be_bytes(),
+        ]
+        .concat();
+
+        Block::block_hash(&block_hash_data)
+    }
+
+    /// Whether the stored hash matches the block contents and its difficulty.
+    pub fn has_valid_hash(&self) -> bool {
+        let hash = Block::calculate_hash(
+            self.timestamp,
+            &self.data,
+            &self.prev_hash,
+            self.difficulty,
+            self.nonce,
+        );
+
+        hash == self.hash && Block::meets_difficulty(&hash, self.difficulty)
+    }
+
+    /// A hash meets a difficulty when its first `difficulty` bits are zero.
+    pub fn meets_difficulty(hash: &BlockHash, difficulty: u32) -> bool {
+        let zero_bytes = (difficulty / 8) as usize;
+        if zero_bytes >= hash.len() {
+            return hash.iter().all(|byte| *byte == 0);
+        }
+
+        let leftover_target = 255 / 2u8.pow(difficulty % 8);
+        hash[..zero_bytes].iter().all(|byte| *byte == 0)
+            && (hash[zero_bytes] | leftover_target) <= leftover_target
+    }
+
+    pub fn block_hash(bytes: &[u8]) -> BlockHash {
         let mut hasher = Sha256::new();
         hasher.update(bytes);
         let res: BlockHash = hasher
@@ -95,14 +249,14 @@ mod test {
     fn block_creation_test() {
         let timestamp = 5;
         let data = b"".to_vec();
-        let hash = Block::block_hash(&b"".to_vec());
-        let prev_hash = Block::block_hash(&b"".to_vec());
+        let hash = Block::block_hash(b"");
+        let prev_hash = Block::block_hash(b"");
         let block = Block {
             timestamp,
             block_number: 0,
             data: data.clone(),
-            hash: hash.clone(),
-            prev_hash: prev_hash.clone(),
+            hash,
+            prev_hash,
             difficulty: 4,
             nonce: 4,
         };
@@ -112,4 +266,52 @@ mod test {
         assert_eq!(hash, block.get_hash());
         assert_eq!(prev_hash, block.get_prev_hash());
     }
+
+    #[test]
+    fn meets_difficulty_test() {
+        let mut hash = [255; 32];
+        assert!(Block::meets_difficulty(&hash, 0));
+        assert!(!Block::meets_difficulty(&hash, 1));
+
+        hash[0] = 0;
+        hash[1] = 0b0001_1111;
+        assert!(Block::meets_difficulty(&hash, 11));
+        assert!(!Block::meets_difficulty(&hash, 12));
+        assert!(Block::meets_difficulty(&[0; 32], 256));
+    }
+
+    #[test]
+    fn genesis_test() {
+        let genesis = Block::genesis();
+
+        assert!(genesis.has_valid_hash());
+        assert_eq!(genesis.get_block_number(), 0);
+        assert_eq!(genesis, Block::genesis());
+    }
+
+    #[test]
+    fn header_test() {
+        let block = Block::default();
+        let header = block.header();
+
+        assert_eq!(header.hash, block.get_hash());
+        assert_eq!(header.prev_hash, block.get_prev_hash());
+        assert_eq!(header.block_number, block.get_block_number());
+        assert_eq!(header.work(), 1);
+        assert_eq!(header.data_root, Block::data_root(&block.data));
+        assert!(!header.has_valid_pow());
+        assert!(Block::genesis().header().has_valid_pow());
+    }
+
+    #[test]
+    fn forged_header_test() {
+        let mut header = Block::genesis().header();
+        header.hash = [0; 32];
+        header.difficulty = 127;
+        assert!(!header.has_valid_pow());
+
+        let mut header = Block::genesis().header();
+        header.data_root = Block::data_root(b"other data");
+        assert!(!header.has_valid_pow());
+    }
 }
@@ -1,75 +1,159 @@
-use crate::blockchain::block::{Block, BlockHash};
-use crate::storage::Client;
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::events::{ChainEvent, EventBus};
+use crate::blockchain::sync::HeaderChain;
+use crate::blockchain::transaction::TxHash;
+use crate::config::NodeConfig;
+use crate::storage::cache::CacheStats;
+use crate::storage::{migration, Client, NotFound};
 use anyhow::Result;
-use core::panic;
-use crossbeam::channel::Sender;
-use std::thread::JoinHandle;
+use std::collections::{HashMap, VecDeque};
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::task::{spawn_blocking, JoinHandle};
 
 pub const BLOCK_TIME: u32 = 1000 * 5; // 5 seconds
 pub const SYNC_NODE_ID: usize = 0;
+/// Most blocks kept waiting for their parent, the oldest go first.
+pub const MAX_ORPHANS: usize = 100;
+/// How far past our clock, in seconds, a received block may be dated.
+pub const MAX_FUTURE_DRIFT: u64 = 60 * 60 * 2; // 2 hours
+/// Fewest block bodies a pruned node keeps, reorgs deeper than what it
+/// keeps fail.
+pub const MIN_PRUNE_KEEP: usize = 10;
+
+/// What `Chain::receive_block` did with a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// Added on top of the tip.
+    Extended,
+    /// Made a side chain with more work the main chain.
+    Reorganized,
+    /// Stored on a side chain with less work than the main one.
+    SideChain,
+    /// Parent unknown, kept until it arrives.
+    Orphan,
+    Known,
+    Invalid,
+}
 
 pub struct Chain {
     client: Client,
     pub hashes: Vec<BlockHash>,
+    /// Sum of the work of the blocks in `hashes`.
+    work: u128,
     pub synced: bool,
+    /// Height of the chain being synced from.
+    pub sync_target: usize,
+    /// Blocks waiting for their parent, by parent hash.
+    orphans: HashMap<BlockHash, Vec<Block>>,
+    /// Parent and hash of every orphan, oldest first.
+    orphan_order: VecDeque<(BlockHash, BlockHash)>,
+    events: EventBus,
+    /// Target milliseconds between blocks.
+    block_time: u32,
+    /// How many of the last block bodies to keep, all when `None`.
+    prune: Option<usize>,
 }
 
-impl Default for Chain {
-    fn default() -> Self {
-        let node_id = dotenv::var("NODE_ID").unwrap();
-        Chain {
-            client: Client::new(node_id).unwrap(),
+impl Chain {
+    /// The chain of the node blocks are synced from, read-only.
+    pub async fn new(config: &NodeConfig) -> Result<Self> {
+        // the sync node migrates its own data, reading it needs it done
+        let mut client = Client::open_view(SYNC_NODE_ID.to_string(), &config.db).await?;
+        migration::check(&mut client).await?;
+
+        Ok(Chain {
+            client,
             hashes: vec![],
+            work: 0,
             synced: false,
-        }
+            sync_target: 0,
+            orphans: HashMap::new(),
+            orphan_order: VecDeque::new(),
+            events: EventBus::new(),
+            block_time: config.block_time,
+            prune: None,
+        })
     }
-}
 
-impl Chain {
-    pub fn new() -> Self {
-        Chain {
-            client: Client::new(SYNC_NODE_ID.to_string()).unwrap(),
+    /// Opens the chain kept by `client`, storing the genesis block if empty.
+    pub async fn with_client(client: Client) -> Result<Self> {
+        let mut chain = Chain {
+            client,
             hashes: vec![],
+            work: 0,
             synced: false,
+            sync_target: 0,
+            orphans: HashMap::new(),
+            orphan_order: VecDeque::new(),
+            events: EventBus::new(),
+            block_time: BLOCK_TIME,
+            prune: None,
+        };
+
+        if chain.client.get_block_by_number(0).await.is_err() {
+            let genesis = Block::genesis();
+            chain.client.save_block(&genesis).await?;
+        }
+
+        let last_block_number = chain.client.get_block_count().await;
+        for header in chain.client.get_headers(0, last_block_number).await? {
+            chain.push_hash(&header);
         }
+
+        Ok(chain)
     }
 
-    fn sync_chain(mut chain: Chain, send_finish: Sender<bool>) -> Result<JoinHandle<Chain>> {
-        let last_block = chain.client.get_last_block()?;
-        let last_block_number = last_block.get_block_number();
+    async fn sync_chain(mut chain: Chain, config: &NodeConfig) -> Result<JoinHandle<Chain>> {
+        let last_block_number = match chain.client.get_last_block().await {
+            Ok(last_block) => last_block.get_block_number(),
+            // an empty sync node has nothing to copy, the node starts on its own
+            Err(err) if err.is::<NotFound>() => {
+                chain.set_synced(true);
+                return Ok(tokio::spawn(async move { chain }));
+            }
+            Err(err) => return Err(err),
+        };
+        let checkpoints = config.checkpoints()?;
+        let node_id = config.node_id.clone();
+        let mut target = Client::from_config(config).await?;
 
-        let join_handle = std::thread::spawn(move || {
-            let mut cur_block_number = 0;
-            let node_id = dotenv::var("NODE_ID").unwrap();
+        let join_handle = tokio::spawn(async move {
+            chain.sync_target = last_block_number;
 
             if node_id == "0" {
+                chain.set_synced(true);
                 return chain;
             }
 
-            loop {
-                let nxt_block = chain
-                    .client
-                    .get_block_by_number(cur_block_number)
-                    .unwrap_or(Block::default());
-
-                let is_valid = chain.add_validate_block(&nxt_block).unwrap();
-
-                if nxt_block.get_block_number() == last_block_number && is_valid {
-                    break;
-                } else if is_valid {
-                    cur_block_number += 1;
-                } else {
-                    panic!("INVALID CHAIN");
+            // headers first: PoW, linkage and checkpoints are checked before
+            // any block body is downloaded
+            let mut header_chain = HeaderChain::new(checkpoints);
+            let headers = chain
+                .client
+                .get_headers(0, last_block_number)
+                .await
+                .unwrap();
+
+            if let Err(err) = header_chain.accept(headers) {
+                panic!("INVALID CHAIN: {}", err);
+            }
+
+            for header in header_chain.headers() {
+                let nxt_block = chain.client.get_block_by_hash(&header.hash).await.unwrap();
+
+                if nxt_block.header() != *header
+                    || !chain.add_validate_block(&nxt_block).await.unwrap()
+                {
+                    panic!(
+                        "INVALID CHAIN: block {} does not match its header",
+                        header.block_number
+                    );
                 }
 
-                chain.client.set_node_id(node_id.clone());
-                chain.client.save_block(&nxt_block).unwrap();
-                chain.client.set_node_id(SYNC_NODE_ID.to_string());
+                target.save_block(&nxt_block).await.unwrap();
             }
 
             chain.set_synced(true);
-            send_finish.send(true).unwrap();
 
             chain
         });
@@ -77,40 +161,40 @@ impl Chain {
         Ok(join_handle)
     }
 
-    pub fn sync(mut self, send_finish: Sender<bool>) -> Result<JoinHandle<Chain>> {
-        let sync_handler = Chain::sync_chain(self, send_finish)?;
+    /// Copies the sync node's chain into the configured node's, checking it on the way.
+    pub async fn sync(self, config: &NodeConfig) -> Result<JoinHandle<Chain>> {
+        let sync_handler = Chain::sync_chain(self, config).await?;
 
         Ok(sync_handler)
     }
 
-    pub fn add_validate_block(&mut self, block: &Block) -> Result<bool> {
-        let timestamp = block.get_timestamp();
-        let data = block.get_data();
-        let difficulty = block.get_difficulty();
-        let nonce = block.get_nonce();
-        let last_block = self.get_last_block()?;
-
-        let block_hash_data = [
-            &timestamp.to_be_bytes(),
-            &data[..],
-            &last_block.get_hash()[..],
-            &difficulty.to_be_bytes(),
-            &nonce.to_be_bytes(),
-        ]
-        .concat();
-
-        let hash = Block::block_hash(&block_hash_data);
+    pub async fn add_validate_block(&mut self, block: &Block) -> Result<bool> {
+        let prev_hash = match self.hashes.last() {
+            Some(_) => self.get_last_block().await?.get_hash(),
+            None => [0; 32],
+        };
 
-        if hash != block.get_hash() {
+        let hash = Block::calculate_hash(
+            block.get_timestamp(),
+            block.get_data(),
+            &prev_hash,
+            block.get_difficulty(),
+            block.get_nonce(),
+        );
+
+        if hash != block.get_hash()
+            || block.get_prev_hash() != prev_hash
+            || !Block::meets_difficulty(&hash, block.get_difficulty())
+        {
             //ERROR
             return Ok(false);
         }
 
-        self.hashes.push(hash);
+        self.push_hash(&block.header());
         Ok(true)
     }
 
-    pub fn mine_block(&mut self, data: Vec<u8>, hash: BlockHash, nonce: u32) -> Result<bool> {
+    pub async fn mine_block(&mut self, data: Vec<u8>, hash: BlockHash, nonce: u32) -> Result<bool> {
         let start = SystemTime::now();
 
         if self.hashes.is_empty() {
@@ -120,31 +204,304 @@ impl Chain {
         let timestamp = start
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards");
-        let nxt_block = self.create_next_block(timestamp.as_secs(), nonce, hash, data)?;
+        let nxt_block = self
+            .create_next_block(timestamp.as_secs(), nonce, hash, data)
+            .await?;
+
+        self.client.save_block(&nxt_block).await?;
+        self.push_hash(&nxt_block.header());
+        Ok(true)
+    }
+
+    /// Does the proof of work for a block on top of the tip and adds it.
+    pub async fn mine_next_block(&mut self, timestamp: u64, data: Vec<u8>) -> Result<Block> {
+        let nxt_block = Chain::solve(self.next_block(timestamp, data).await?).await;
+
+        self.add_mined_block(&nxt_block).await?;
+        Ok(nxt_block)
+    }
 
-        self.client.save_block(&nxt_block)?;
-        self.hashes.push(nxt_block.get_hash());
+    /// The next block on the tip but for its proof of work, to be done by
+    /// `Chain::solve` without holding the chain.
+    pub async fn next_block(&mut self, timestamp: u64, data: Vec<u8>) -> Result<Block> {
+        self.create_next_block(timestamp, 0, [0; 32], data).await
+    }
+
+    /// Adds a block mined with `Chain::solve`. Returns false, adding
+    /// nothing, when the tip moved on while it was mined.
+    pub async fn add_mined_block(&mut self, block: &Block) -> Result<bool> {
+        if self.hashes.last() != Some(&block.get_prev_hash()) {
+            return Ok(false);
+        }
+
+        self.client.save_block(block).await?;
+        self.push_hash(&block.header());
+        self.events.publish(ChainEvent::MinedBlock(block.clone()));
+        self.connected(block);
+        self.prune().await?;
         Ok(true)
     }
 
-    pub fn create_next_block(
+    /// Publishes what happens to the main chain from now on on `events`.
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    /// Milliseconds between blocks difficulty aims for, `BLOCK_TIME` by default.
+    pub fn set_block_time(&mut self, block_time: u32) {
+        self.block_time = block_time;
+    }
+
+    /// Keeps only the last `keep` block bodies from now on, all of them
+    /// with `None`. Call `prune` to catch up right away.
+    pub fn set_prune(&mut self, keep: Option<usize>) {
+        self.prune = keep;
+    }
+
+    /// Deletes the bodies of main chain blocks before the last `keep`, the
+    /// genesis block aside. Headers, the number index and the transaction
+    /// index stay.
+    pub async fn prune(&mut self) -> Result<()> {
+        let keep = match self.prune {
+            Some(keep) => keep,
+            None => return Ok(()),
+        };
+        let first_kept = self.hashes.len().saturating_sub(keep);
+        let pruned_height = self.client.get_pruned_height().await.max(1);
+        if first_kept <= pruned_height {
+            return Ok(());
+        }
+
+        for hash in &self.hashes[pruned_height..first_kept] {
+            self.client.prune_block(hash).await?;
+        }
+        self.client.set_pruned_height(first_kept).await?;
+
+        Ok(())
+    }
+
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    fn rejected(&self, block: &Block, reason: &str) -> Result<BlockStatus> {
+        self.events.publish(ChainEvent::BlockRejected {
+            hash: block.get_hash(),
+            reason: reason.to_string(),
+        });
+        Ok(BlockStatus::Invalid)
+    }
+
+    fn connected(&self, block: &Block) {
+        self.events.publish(ChainEvent::NewBlock(block.clone()));
+        self.events.publish(ChainEvent::NewHead(block.header()));
+    }
+
+    /// Adds a block from anywhere in the block tree: extends the tip,
+    /// switches to its branch when that has more work, or keeps it as a
+    /// side chain or orphan.
+    pub async fn receive_block(&mut self, block: &Block) -> Result<BlockStatus> {
+        let status = self.accept_block(block).await?;
+
+        if matches!(
+            status,
+            BlockStatus::Extended | BlockStatus::Reorganized | BlockStatus::SideChain
+        ) {
+            self.connect_orphans(block.get_hash()).await?;
+        }
+        if matches!(status, BlockStatus::Extended | BlockStatus::Reorganized) {
+            self.prune().await?;
+        }
+
+        Ok(status)
+    }
+
+    async fn accept_block(&mut self, block: &Block) -> Result<BlockStatus> {
+        if self.client.has_block(&block.get_hash()).await {
+            return Ok(BlockStatus::Known);
+        }
+        if !block.has_valid_hash() {
+            return self.rejected(block, "hash does not match the block or its difficulty");
+        }
+
+        if self.hashes.last() == Some(&block.get_prev_hash()) {
+            if block.get_block_number() != self.hashes.len() {
+                return self.rejected(block, "block number does not follow its parent");
+            }
+            let parent = self
+                .client
+                .get_header_by_hash(&block.get_prev_hash())
+                .await?;
+            if let Some(reason) = Chain::timestamp_error(block, &parent) {
+                return self.rejected(block, reason);
+            }
+            if block.get_difficulty() != self.difficulty_after(&parent).await? {
+                return self.rejected(block, "difficulty does not follow its parent");
+            }
+
+            self.client.save_block(block).await?;
+            self.push_hash(&block.header());
+            self.connected(block);
+            return Ok(BlockStatus::Extended);
+        }
+
+        // a header is enough, the parent's body may be pruned
+        let parent = match self.client.get_header_by_hash(&block.get_prev_hash()).await {
+            Ok(parent) => parent,
+            Err(_) => {
+                self.add_orphan(block);
+                return Ok(BlockStatus::Orphan);
+            }
+        };
+        if block.get_block_number() != parent.block_number + 1 {
+            return self.rejected(block, "block number does not follow its parent");
+        }
+        if let Some(reason) = Chain::timestamp_error(block, &parent) {
+            return self.rejected(block, reason);
+        }
+        if block.get_difficulty() != self.difficulty_after(&parent).await? {
+            return self.rejected(block, "difficulty does not follow its parent");
+        }
+
+        self.client.save_side_block(block).await?;
+        self.reorganize(block).await
+    }
+
+    /// Why `block` cannot be dated as it is on top of `parent`: it must come
+    /// after its parent and not too far past our clock.
+    fn timestamp_error(block: &Block, parent: &BlockHeader) -> Option<&'static str> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
+        if block.get_timestamp() <= parent.timestamp {
+            Some("timestamp is not after its parent")
+        } else if block.get_timestamp() > now.saturating_add(MAX_FUTURE_DRIFT) {
+            Some("timestamp is too far in the future")
+        } else {
+            None
+        }
+    }
+
+    /// Keeps a block until its parent arrives, dropping the oldest orphan
+    /// when there are too many.
+    fn add_orphan(&mut self, block: &Block) {
+        let (parent, hash) = (block.get_prev_hash(), block.get_hash());
+        if self.orphan_order.contains(&(parent, hash)) {
+            return;
+        }
+
+        if self.orphan_order.len() >= MAX_ORPHANS {
+            if let Some((oldest_parent, oldest)) = self.orphan_order.pop_front() {
+                if let Some(waiting) = self.orphans.get_mut(&oldest_parent) {
+                    waiting.retain(|orphan| orphan.get_hash() != oldest);
+                    if waiting.is_empty() {
+                        self.orphans.remove(&oldest_parent);
+                    }
+                }
+            }
+        }
+
+        self.orphans.entry(parent).or_default().push(block.clone());
+        self.orphan_order.push_back((parent, hash));
+    }
+
+    async fn connect_orphans(&mut self, parent: BlockHash) -> Result<()> {
+        let mut parents = vec![parent];
+
+        while let Some(parent) = parents.pop() {
+            self.orphan_order
+                .retain(|(waiting_on, _)| *waiting_on != parent);
+            for orphan in self.orphans.remove(&parent).unwrap_or_default() {
+                if matches!(
+                    self.accept_block(&orphan).await?,
+                    BlockStatus::Extended | BlockStatus::Reorganized | BlockStatus::SideChain
+                ) {
+                    parents.push(orphan.get_hash());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Switches the main chain to the branch ending at `tip` if it has more
+    /// work. The branch is found through headers, only its own bodies are
+    /// loaded.
+    async fn reorganize(&mut self, tip: &Block) -> Result<BlockStatus> {
+        let mut headers = vec![tip.header()];
+        let fork = loop {
+            let parent_hash = headers.last().unwrap().prev_hash;
+            let parent = self.client.get_header_by_hash(&parent_hash).await?;
+
+            if self.hashes.get(parent.block_number) == Some(&parent_hash) {
+                break parent.block_number;
+            }
+            headers.push(parent);
+        };
+        headers.reverse();
+
+        let branch_work: u128 = headers.iter().map(BlockHeader::work).sum();
+        let mut main_work = 0;
+        for hash in &self.hashes[fork + 1..] {
+            main_work += self.client.get_header_by_hash(hash).await?.work();
+        }
+
+        if branch_work <= main_work {
+            return Ok(BlockStatus::SideChain);
+        }
+
+        let mut branch = vec![];
+        for header in &headers[..headers.len() - 1] {
+            branch.push(self.client.get_block_by_hash(&header.hash).await?);
+        }
+        branch.push(tip.clone());
+
+        let old_len = self.hashes.len();
+        let old_tip = *self.hashes.last().unwrap();
+        self.hashes.truncate(fork + 1);
+        self.work -= main_work;
+        for block in &branch {
+            self.client.save_block(block).await?;
+            self.push_hash(&block.header());
+        }
+        for stale in self.hashes.len()..old_len {
+            self.client.unindex_block(stale).await?;
+        }
+
+        self.events.publish(ChainEvent::Reorg {
+            old_tip,
+            new_tip: tip.get_hash(),
+            fork_height: fork,
+            depth: old_len - fork - 1,
+        });
+        for block in &branch {
+            self.connected(block);
+        }
+
+        Ok(BlockStatus::Reorganized)
+    }
+
+    pub async fn create_next_block(
         &mut self,
         timestamp: u64,
         nonce: u32,
         hash: BlockHash,
         data: Vec<u8>,
     ) -> Result<Block> {
-        let block = self.get_last_block()?;
-        let difficulty = self.get_difficulty()?;
+        let block = self.get_last_block().await?;
+        let difficulty = self.get_difficulty().await?;
 
         let result = Block {
             block_number: self.hashes.len(),
-            timestamp,
+            // blocks must be dated after their parent
+            timestamp: timestamp.max(block.get_timestamp().saturating_add(1)),
             difficulty,
             nonce,
-            data: data.clone(),
+            data,
             hash,
-            prev_hash: block.get_hash().clone(),
+            prev_hash: block.get_hash(),
         };
 
         Ok(result)
@@ -154,68 +511,125 @@ impl Chain {
         self.synced = is_synced;
     }
 
-    pub fn get_block_by_chain_index(&mut self, index: usize) -> Result<Block> {
-        let block_hash = &self.hashes[index];
-        let block = self.client.get_block_by_hash(block_hash)?;
+    pub fn height(&self) -> usize {
+        self.hashes.len().saturating_sub(1)
+    }
+
+    /// How the storage block cache is doing.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.client.cache_stats()
+    }
+
+    /// Share of the sync target reached, 1.0 once synced.
+    pub fn sync_progress(&self) -> f64 {
+        if self.synced || self.sync_target == 0 {
+            return if self.synced { 1.0 } else { 0.0 };
+        }
+
+        (self.height() as f64 / self.sync_target as f64).min(1.0)
+    }
+
+    /// Sum of the work of every block on the main chain.
+    pub fn total_work(&self) -> u128 {
+        self.work
+    }
+
+    /// Puts a block on top of the main chain index.
+    fn push_hash(&mut self, header: &BlockHeader) {
+        self.hashes.push(header.hash);
+        self.work += header.work();
+    }
+
+    /// The main chain block a transaction was included in, if any.
+    pub async fn find_transaction(&mut self, tx_hash: &TxHash) -> Result<Option<Block>> {
+        let block_hash = match self.client.get_tx_block(tx_hash).await {
+            Ok(block_hash) => block_hash,
+            Err(_) => return Ok(None),
+        };
+        let block = self.client.get_block_by_hash(&block_hash).await?;
+
+        // the index may point at a block a reorg took off the main chain
+        if self.hashes.get(block.get_block_number()) != Some(&block_hash) {
+            return Ok(None);
+        }
+
+        Ok(Some(block))
+    }
+
+    pub async fn get_block_by_chain_index(&mut self, index: usize) -> Result<Block> {
+        let block_hash = self
+            .hashes
+            .get(index)
+            .ok_or_else(|| NotFound(format!("block number {}", index)))?;
+        let block = self.client.get_block_by_hash(block_hash).await?;
 
         Ok(block)
     }
 
-    pub fn get_last_block(&mut self) -> Result<Block> {
-        let block = self.get_block_by_chain_index(self.hashes.len() - 1)?;
+    pub async fn get_block_by_hash(&mut self, block_hash: &BlockHash) -> Result<Block> {
+        self.client.get_block_by_hash(block_hash).await
+    }
+
+    pub async fn get_last_block(&mut self) -> Result<Block> {
+        let block = self.get_block_by_chain_index(self.hashes.len() - 1).await?;
 
         Ok(block)
     }
 
-    pub fn generate_next_block_hash(
-        &mut self,
-        timestamp: u64,
-        data: Vec<u8>,
-    ) -> JoinHandle<(BlockHash, u32)> {
-        let block = self.get_last_block().unwrap_or(Block::default());
-        let nxt_difficulty = self.get_difficulty().unwrap_or(0);
-
-        std::thread::spawn(move || loop {
-            let mut nonce: u32 = 0;
-            let target_zeroes: &[u8] = &vec![0; (nxt_difficulty / 8) as usize];
-            let leftover_target = 255 / 2u8.pow((nxt_difficulty % 8) as u32);
-            let data = &data;
-            let block_hash_data = [
-                &timestamp.to_be_bytes(),
-                &data[..],
-                &block.get_hash()[..],
-                &nxt_difficulty.to_be_bytes(),
-                &nonce.to_be_bytes(),
-            ]
-            .concat();
-            let hash = Block::block_hash(&block_hash_data);
-            let leftover_byte = hash[target_zeroes.len()];
-
-            if hash.starts_with(target_zeroes)
-                && (leftover_byte | leftover_target) <= leftover_target
-            {
-                break (hash, nonce);
+    /// Finds a nonce meeting the block's difficulty and sets its hash.
+    pub async fn solve(mut block: Block) -> Block {
+        // hashing is CPU bound, keep it off the async workers
+        spawn_blocking(move || {
+            let data_root = Block::data_root(&block.data);
+
+            loop {
+                let hash = Block::header_hash(
+                    block.timestamp,
+                    &data_root,
+                    &block.prev_hash,
+                    block.difficulty,
+                    block.nonce,
+                );
+
+                if Block::meets_difficulty(&hash, block.difficulty) {
+                    block.hash = hash;
+                    break block;
+                }
+                block.nonce += 1;
             }
-            nonce += 1;
         })
+        .await
+        .expect("mining task panicked")
     }
 
-    pub fn get_difficulty(&mut self) -> Result<u32> {
-        let last_block = self.get_last_block()?;
+    /// Difficulty of the next block on the tip.
+    pub async fn get_difficulty(&mut self) -> Result<u32> {
+        let last_block = self.get_last_block().await?;
 
-        let prev_timestamp: u32 = if self.hashes.len() > 1 {
-            let prev_block = self
-                .client
-                .get_block_by_hash(&self.hashes[self.hashes.len() - 2])?;
-            prev_block.get_difficulty()
-        } else {
-            0
+        self.difficulty_after(&last_block.header()).await
+    }
+
+    /// Difficulty a block on top of `parent` must have: down when the
+    /// parent took longer than the block time, up otherwise.
+    async fn difficulty_after(&mut self, parent: &BlockHeader) -> Result<u32> {
+        let prev_timestamp: u64 = match parent.block_number {
+            0 => 0,
+            _ => {
+                self.client
+                    .get_header_by_hash(&parent.prev_hash)
+                    .await?
+                    .timestamp
+            }
         };
+        let elapsed = parent
+            .timestamp
+            .saturating_sub(prev_timestamp)
+            .saturating_mul(1000);
 
-        let res = if last_block.get_difficulty() - prev_timestamp > BLOCK_TIME {
-            last_block.get_difficulty() - 1
+        let res = if elapsed > self.block_time as u64 {
+            parent.difficulty.saturating_sub(1)
         } else {
-            last_block.get_difficulty() + 1
+            parent.difficulty.saturating_add(1)
         };
 
         Ok(res)
@@ -228,15 +642,12 @@ impl Chain {
         }
     }
 
-    pub fn is_valid_chain(&mut self) -> Result<bool> {
+    pub async fn is_valid_chain(&mut self) -> Result<bool> {
         for i in 0..self.hashes.len() - 1 {
-            let block = self.get_block_by_chain_index(i)?;
-            let nxt_block = self.get_block_by_chain_index(i + 1)?;
-
-            let hash1 = block.get_hash();
-            let hash2 = nxt_block.get_hash();
+            let block = self.get_block_by_chain_index(i).await?;
+            let nxt_block = self.get_block_by_chain_index(i + 1).await?;
 
-            if !block.get_hash().eq(&nxt_block.get_hash()) {
+            if nxt_block.get_prev_hash() != block.get_hash() {
                 return Ok(false);
             }
         }
@@ -249,17 +660,302 @@ pub fn replace_chain(mut target: &Chain, copy: &Chain) -> () {}
 
 #[cfg(test)]
 mod test {
-    use crate::blockchain::block::*;
     use crate::blockchain::chain::*;
+    use crate::blockchain::transaction::{encode_transactions, Transaction};
+    use crate::storage::MemoryStore;
 
-    fn create_chain() -> Result<Chain> {
-        let mut chain = Chain::default()?;
-        chain.mine_block(0, &b"first block data".to_vec());
+    async fn memory_chain(node_id: &str) -> Chain {
+        let client = Client::memory(node_id.to_string(), MemoryStore::default());
+        Chain::with_client(client).await.unwrap()
+    }
+
+    #[allow(dead_code)]
+    async fn create_chain() -> Result<Chain> {
+        let mut chain = Chain::new(&NodeConfig::default()).await?;
+        chain
+            .mine_block(b"first block data".to_vec(), [0; 32], 0)
+            .await?;
         Ok(chain)
     }
 
-    fn get_difficulty_test() {
-        let mut chain = create_chain();
+    #[tokio::test]
+    async fn stale_mined_block_test() -> Result<()> {
+        let mut chain = memory_chain("miner").await;
+        let template = chain.next_block(10, b"slow".to_vec()).await?;
+        let fast = chain.mine_next_block(10, b"fast".to_vec()).await?;
+
+        let slow = Chain::solve(template).await;
+        assert!(slow.has_valid_hash());
+        assert!(!chain.add_mined_block(&slow).await?);
+        assert_eq!(chain.hashes.last(), Some(&fast.get_hash()));
+
+        let next = Chain::solve(chain.next_block(20, vec![]).await?).await;
+        assert!(chain.add_mined_block(&next).await?);
+        assert_eq!(chain.hashes.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn receive_block_test() -> Result<()> {
+        let mut miner = memory_chain("miner").await;
+        let mut other = memory_chain("other").await;
+
+        let first = miner.mine_next_block(10, b"first".to_vec()).await?;
+        let second = miner.mine_next_block(20, b"second".to_vec()).await?;
+
+        assert_eq!(other.receive_block(&second).await?, BlockStatus::Orphan);
+        assert_eq!(other.receive_block(&first).await?, BlockStatus::Extended);
+        assert_eq!(other.hashes, miner.hashes);
+        assert_eq!(other.receive_block(&first).await?, BlockStatus::Known);
+
+        let mut events = other.events().subscribe();
+        let mut invalid = miner.mine_next_block(30, b"third".to_vec()).await?;
+        invalid.data = b"tampered".to_vec();
+        assert_eq!(other.receive_block(&invalid).await?, BlockStatus::Invalid);
+        assert!(matches!(
+            events.try_recv()?,
+            ChainEvent::BlockRejected { hash, .. } if hash == invalid.get_hash()
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn orphans_test() -> Result<()> {
+        let mut miner = memory_chain("miner").await;
+        let mut other = memory_chain("other").await;
+
+        let first = miner.mine_next_block(10, vec![]).await?;
+        let mut blocks = vec![];
+        for i in 0..=MAX_ORPHANS as u64 {
+            blocks.push(miner.mine_next_block(20 + i * 10, vec![]).await?);
+        }
+
+        for block in &blocks {
+            assert_eq!(other.receive_block(block).await?, BlockStatus::Orphan);
+            assert_eq!(other.receive_block(block).await?, BlockStatus::Orphan);
+        }
+        assert_eq!(other.orphan_order.len(), MAX_ORPHANS);
+        assert_eq!(
+            other.orphans.values().map(Vec::len).sum::<usize>(),
+            MAX_ORPHANS
+        );
+
+        // the oldest orphan was dropped, the others wait for it
+        assert_eq!(other.receive_block(&first).await?, BlockStatus::Extended);
+        assert_eq!(other.height(), 1);
+        assert_eq!(
+            other.receive_block(&blocks[0]).await?,
+            BlockStatus::Extended
+        );
+        assert_eq!(other.hashes, miner.hashes);
+        assert!(other.orphans.is_empty() && other.orphan_order.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reorganize_test() -> Result<()> {
+        let mut chain = memory_chain("main").await;
+        let mut fork = memory_chain("fork").await;
+
+        chain.mine_next_block(10, b"main 1".to_vec()).await?;
+        let main = chain.mine_next_block(20, b"main 2".to_vec()).await?;
+        let mut side = vec![];
+        for i in 1..=3 {
+            side.push(
+                fork.mine_next_block(i * 10, format!("fork {}", i).into_bytes())
+                    .await?,
+            );
+        }
+
+        let mut events = chain.events().subscribe();
+        let old_tip = *chain.hashes.last().unwrap();
+        assert_eq!(chain.client.get_block_by_number(2).await?, main);
+
+        assert_eq!(chain.receive_block(&side[0]).await?, BlockStatus::SideChain);
+        assert_eq!(chain.receive_block(&side[1]).await?, BlockStatus::SideChain);
+        assert_eq!(chain.hashes.len(), 3);
+        assert!(events.try_recv().is_err());
+
+        assert_eq!(
+            chain.receive_block(&side[2]).await?,
+            BlockStatus::Reorganized
+        );
+        assert_eq!(
+            events.try_recv()?,
+            ChainEvent::Reorg {
+                old_tip,
+                new_tip: side[2].get_hash(),
+                fork_height: 0,
+                depth: 2,
+            }
+        );
+        let mut connected = 0;
+        while events.try_recv().is_ok() {
+            connected += 1;
+        }
+        assert_eq!(connected, 6);
+        assert_eq!(chain.total_work(), fork.total_work());
+        assert_eq!(chain.hashes, fork.hashes);
+        assert_eq!(chain.get_last_block().await?, side[2]);
+        // the cached number index follows the reorg
+        assert_eq!(chain.client.get_block_by_number(2).await?, side[1]);
+        assert!(chain.cache_stats().hits > 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_transaction_test() -> Result<()> {
+        let mut chain = memory_chain("find").await;
+        let mut fork = memory_chain("find fork").await;
+        let transaction = Transaction::new(1, b"payment".to_vec());
+        let data = encode_transactions(std::slice::from_ref(&transaction));
+
+        assert_eq!(chain.find_transaction(&transaction.hash()).await?, None);
+        let block = chain.mine_next_block(10, data).await?;
+        assert_eq!(
+            chain.find_transaction(&transaction.hash()).await?,
+            Some(block)
+        );
+
+        let mut side = vec![];
+        for i in 1..=2 {
+            side.push(fork.mine_next_block(i * 10, vec![i as u8]).await?);
+        }
+        for block in &side {
+            chain.receive_block(block).await?;
+        }
+        assert_eq!(chain.find_transaction(&transaction.hash()).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_test() -> Result<()> {
+        let mut chain = memory_chain("status").await;
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.total_work(), 1);
+
+        chain.mine_next_block(10, vec![]).await?;
+        chain.mine_next_block(11, vec![]).await?;
+        assert_eq!(chain.height(), 2);
+        assert_eq!(chain.total_work(), 1 + 1 + 2);
+
+        chain.sync_target = 4;
+        assert_eq!(chain.sync_progress(), 0.5);
+        chain.set_synced(true);
+        assert_eq!(chain.sync_progress(), 1.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn difficulty_test() -> Result<()> {
+        let mut chain = memory_chain("difficulty").await;
+
+        chain.mine_next_block(10, vec![]).await?;
+        assert_eq!(chain.get_difficulty().await?, 0);
+
+        chain.mine_next_block(11, vec![]).await?;
+        assert_eq!(chain.get_last_block().await?.get_difficulty(), 0);
+        assert_eq!(chain.get_difficulty().await?, 1);
+
+        chain.mine_next_block(12, vec![]).await?;
+        chain.mine_next_block(30, vec![]).await?;
+        assert_eq!(chain.get_difficulty().await?, 1);
+
+        // difficulty 0 always has a valid hash, but the rule asks for 1
+        let tip = chain.get_last_block().await?;
+        let easy = Block {
+            timestamp: 31,
+            difficulty: 0,
+            block_number: tip.get_block_number() + 1,
+            nonce: 0,
+            hash: Block::calculate_hash(31, &[], &tip.get_hash(), 0, 0),
+            data: vec![],
+            prev_hash: tip.get_hash(),
+        };
+        assert!(easy.has_valid_hash());
+        assert_eq!(chain.receive_block(&easy).await?, BlockStatus::Invalid);
+        assert_eq!(chain.get_last_block().await?, tip);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timestamp_test() -> Result<()> {
+        let mut chain = memory_chain("timestamp").await;
+        let mut other = memory_chain("other").await;
+        let tip = chain.mine_next_block(10, vec![]).await?;
+        other.receive_block(&tip).await?;
+
+        // mined blocks are dated after their parent whatever the clock says
+        let next = chain.next_block(5, vec![]).await?;
+        assert_eq!(next.get_timestamp(), 11);
+
+        for timestamp in [10, u64::MAX] {
+            let mut block = next.clone();
+            block.timestamp = timestamp;
+            let block = Chain::solve(block).await;
+            assert_eq!(other.receive_block(&block).await?, BlockStatus::Invalid);
+        }
+
+        chain.mine_next_block(u64::MAX / 2, vec![]).await?;
+        assert_eq!(chain.get_difficulty().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prune_test() -> Result<()> {
+        let mut chain = memory_chain("prune").await;
+        chain.set_prune(Some(MIN_PRUNE_KEEP));
+        for timestamp in 1..=15 {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        // 16 blocks with genesis, the bodies of 1 to 5 are gone
+        let pruned = chain.get_block_by_chain_index(5).await.unwrap_err();
+        assert!(pruned.is::<crate::storage::Pruned>());
+        assert!(chain.get_block_by_chain_index(6).await.is_ok());
+        assert!(chain.get_block_by_chain_index(0).await.is_ok());
+        assert_eq!(
+            chain.client.get_header_by_number(3).await?.hash,
+            chain.hashes[3]
+        );
+        assert!(chain.client.has_block(&chain.hashes[3]).await);
+
+        let report = crate::blockchain::verify::verify(&mut chain.client, false).await?;
+        assert!(report.is_valid());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reorganize_past_pruned_test() -> Result<()> {
+        let mut chain = memory_chain("pruned").await;
+        let mut fork = memory_chain("fork").await;
+        chain.set_prune(Some(MIN_PRUNE_KEEP));
+        for timestamp in 1..=15 {
+            let block = chain.mine_next_block(timestamp * 10, vec![]).await?;
+            if timestamp <= 3 {
+                fork.receive_block(&block).await?;
+            }
+        }
+
+        // the fork leaves from block 3, whose body is pruned
+        let mut side = vec![];
+        for i in 1..=14 {
+            side.push(fork.mine_next_block(1000 + i * 10, vec![i as u8]).await?);
+        }
+        assert!(chain
+            .client
+            .get_block_by_hash(&chain.hashes[3])
+            .await
+            .is_err());
+
+        let mut statuses = vec![];
+        for block in &side {
+            statuses.push(chain.receive_block(block).await?);
+        }
+        assert!(!statuses.contains(&BlockStatus::Orphan));
+        assert!(statuses.contains(&BlockStatus::Reorganized));
+        assert_eq!(chain.hashes, fork.hashes);
+        Ok(())
     }
 
     #[test]
@@ -0,0 +1,110 @@
+use rocket::serde::Serialize;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::transaction::Transaction;
+
+/// Events a subscriber may fall behind by before it starts missing some.
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde", tag = "topic", content = "data")]
+pub enum ChainEvent {
+    /// The main chain has a new tip.
+    #[serde(rename = "newHeads")]
+    NewHead(BlockHeader),
+    /// A block joined the main chain, by extending it or through a reorg.
+    #[serde(rename = "newBlocks")]
+    NewBlock(Block),
+    #[serde(rename = "pendingTransactions")]
+    PendingTransaction(Transaction),
+    /// This node mined a block.
+    #[serde(rename = "minedBlock")]
+    MinedBlock(Block),
+    #[serde(rename = "blockRejected")]
+    BlockRejected { hash: BlockHash, reason: String },
+    /// The main chain switched branch above `fork_height`, dropping `depth` blocks.
+    #[serde(rename = "reorg")]
+    Reorg {
+        old_tip: BlockHash,
+        new_tip: BlockHash,
+        fork_height: usize,
+        depth: usize,
+    },
+}
+
+impl ChainEvent {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            ChainEvent::NewHead(_) => "newHeads",
+            ChainEvent::NewBlock(_) => "newBlocks",
+            ChainEvent::PendingTransaction(_) => "pendingTransactions",
+            ChainEvent::MinedBlock(_) => "minedBlock",
+            ChainEvent::BlockRejected { .. } => "blockRejected",
+            ChainEvent::Reorg { .. } => "reorg",
+        }
+    }
+}
+
+/// Fans chain events out to every subscriber. Cloning shares the
+/// subscribers, so the chain and the relay can publish on the same bus.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: Sender<ChainEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = channel(SUBSCRIBER_BUFFER);
+        EventBus { sender }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    pub fn subscribe(&self) -> Receiver<ChainEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Never blocks: a subscriber more than `SUBSCRIBER_BUFFER` events
+    /// behind gets `Lagged` and misses the oldest ones.
+    pub fn publish(&self, event: ChainEvent) {
+        // no subscribers is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::events::*;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    #[test]
+    fn publish_test() {
+        let events = EventBus::new();
+        let mut first = events.subscribe();
+        let second = events.subscribe();
+        drop(second);
+
+        let transaction = Transaction::new(1, b"pending".to_vec());
+        events.publish(ChainEvent::PendingTransaction(transaction.clone()));
+
+        assert_eq!(
+            first.try_recv().unwrap(),
+            ChainEvent::PendingTransaction(transaction)
+        );
+        assert_eq!(events.subscribers(), 1);
+
+        for _ in 0..SUBSCRIBER_BUFFER + 1 {
+            events.publish(ChainEvent::NewHead(Block::genesis().header()));
+        }
+        assert_eq!(first.try_recv(), Err(TryRecvError::Lagged(1)));
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::blockchain::block::Block;
+use crate::blockchain::transaction::{Transaction, TxHash};
+
+pub const MAX_MEMPOOL_SIZE: usize = 10_000;
+
+/// Transactions waiting to get into a block, oldest first. Past
+/// `MAX_MEMPOOL_SIZE` the oldest ones are dropped.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    transactions: HashMap<TxHash, Transaction>,
+    order: VecDeque<TxHash>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool::default()
+    }
+
+    /// Returns whether the transaction was new.
+    pub fn insert(&mut self, transaction: Transaction) -> bool {
+        let hash = transaction.hash();
+        if self.transactions.contains_key(&hash) {
+            return false;
+        }
+
+        if self.order.len() >= MAX_MEMPOOL_SIZE {
+            if let Some(oldest) = self.order.pop_front() {
+                self.transactions.remove(&oldest);
+            }
+        }
+
+        self.transactions.insert(hash, transaction);
+        self.order.push_back(hash);
+        true
+    }
+
+    pub fn contains(&self, hash: &TxHash) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &TxHash) -> Option<&Transaction> {
+        self.transactions.get(hash)
+    }
+
+    pub fn remove(&mut self, hash: &TxHash) -> Option<Transaction> {
+        let transaction = self.transactions.remove(hash)?;
+        self.order.retain(|other| other != hash);
+        Some(transaction)
+    }
+
+    /// Drops the transactions a block made it into the chain with.
+    pub fn remove_included(&mut self, block: &Block) {
+        for transaction in block.transactions().unwrap_or_default() {
+            self.remove(&transaction.hash());
+        }
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        self.order
+            .iter()
+            .filter_map(|hash| self.transactions.get(hash))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::mempool::*;
+
+    #[test]
+    fn insert_and_remove_test() {
+        let mut mempool = Mempool::new();
+        let first = Transaction::new(1, b"first".to_vec());
+        let second = Transaction::new(2, b"second".to_vec());
+
+        assert!(mempool.insert(first.clone()));
+        assert!(!mempool.insert(first.clone()));
+        assert!(mempool.insert(second.clone()));
+        assert_eq!(mempool.transactions(), vec![first.clone(), second.clone()]);
+
+        assert_eq!(mempool.remove(&first.hash()), Some(first.clone()));
+        assert!(!mempool.contains(&first.hash()));
+        assert_eq!(mempool.get(&second.hash()), Some(&second));
+        assert_eq!(mempool.len(), 1);
+    }
+}
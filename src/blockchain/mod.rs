@@ -1,2 +1,9 @@
+pub mod archive;
 pub mod block;
 pub mod chain;
+pub mod events;
+pub mod mempool;
+pub mod snapshot;
+pub mod sync;
+pub mod transaction;
+pub mod verify;
@@ -0,0 +1,225 @@
+use anyhow::{anyhow, bail, Result};
+use hex::encode;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::sync::{Checkpoints, HeaderChain};
+use crate::blockchain::transaction::TxHash;
+use crate::storage::Client;
+
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+/// The chain at some height, enough for a node to start there instead of
+/// at genesis: every header, the bodies difficulty needs on top of the
+/// genesis block, and the state, which is the transaction index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Snapshot {
+    pub version: u16,
+    pub height: usize,
+    pub headers: Vec<BlockHeader>,
+    /// The genesis block, the tip and its parent.
+    pub blocks: Vec<Block>,
+    /// Transaction hash -> hash of the block it was saved with, sorted.
+    pub transactions: Vec<(TxHash, BlockHash)>,
+    pub commitment: BlockHash,
+}
+
+/// Hash of every header hash from genesis to the tip, followed by every
+/// transaction index entry, so trusting it trusts the whole history.
+pub fn state_commitment(
+    headers: &[BlockHeader],
+    transactions: &[(TxHash, BlockHash)],
+) -> BlockHash {
+    let mut bytes = vec![];
+    for header in headers {
+        bytes.extend(header.hash);
+    }
+    for (tx_hash, block_hash) in transactions {
+        bytes.extend(tx_hash);
+        bytes.extend(block_hash);
+    }
+
+    Block::block_hash(&bytes)
+}
+
+impl Snapshot {
+    /// Snapshot of the main chain stored by `client` at `height`, which
+    /// needs every body up to there.
+    pub async fn create(client: &mut Client, height: usize) -> Result<Self> {
+        if height > client.get_block_count().await {
+            bail!("the chain does not reach block {}", height);
+        }
+        if client.get_pruned_height().await > 0 {
+            bail!("block bodies are pruned, a snapshot needs all of them");
+        }
+
+        let mut headers = vec![];
+        let mut blocks = vec![];
+        let mut index = BTreeMap::new();
+        for number in 0..=height {
+            let block = client.get_block_by_number(number).await?;
+            for transaction in block.transactions().unwrap_or_default() {
+                index.insert(transaction.hash(), block.get_hash());
+            }
+            headers.push(block.header());
+            if number == 0 || number + 1 >= height {
+                blocks.push(block);
+            }
+        }
+
+        let transactions: Vec<_> = index.into_iter().collect();
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            height,
+            commitment: state_commitment(&headers, &transactions),
+            headers,
+            blocks,
+            transactions,
+        })
+    }
+
+    pub fn tip(&self) -> Option<&BlockHeader> {
+        self.headers.last()
+    }
+
+    /// Checks the headers like sync does, against `checkpoints`, the bodies
+    /// against the headers, and the state against the commitment, which
+    /// has to be one of `trusted` at this height.
+    pub fn verify(&self, checkpoints: Checkpoints, trusted: &Checkpoints) -> Result<()> {
+        if self.version != SNAPSHOT_VERSION {
+            bail!("unsupported snapshot version {}", self.version);
+        }
+        let tip = self
+            .tip()
+            .ok_or_else(|| anyhow!("snapshot has no headers"))?;
+        if tip.block_number != self.height || self.headers[0] != Block::genesis().header() {
+            bail!(
+                "snapshot headers do not run from genesis to {}",
+                self.height
+            );
+        }
+        HeaderChain::new(checkpoints).accept(self.headers.clone())?;
+
+        for block in &self.blocks {
+            let hash = Block::calculate_hash(
+                block.get_timestamp(),
+                block.get_data(),
+                &block.get_prev_hash(),
+                block.get_difficulty(),
+                block.get_nonce(),
+            );
+            let header = self.headers.get(block.get_block_number());
+            if hash != block.get_hash() || header != Some(&block.header()) {
+                bail!(
+                    "snapshot block {} does not match its header",
+                    block.get_block_number()
+                );
+            }
+        }
+
+        if state_commitment(&self.headers, &self.transactions) != self.commitment {
+            bail!("snapshot state does not match its commitment");
+        }
+        if trusted.get(&self.height) != Some(&self.commitment) {
+            bail!(
+                "commitment 0x{} at {} is not a trusted snapshot",
+                encode(self.commitment),
+                self.height
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Stores the snapshot as the chain of `client`, which has to be empty
+    /// but for the genesis block. Blocks after the snapshot then come in
+    /// as usual.
+    pub async fn restore(&self, client: &mut Client) -> Result<()> {
+        if client.get_block_count().await > 0 {
+            bail!("node {} already has a chain", client.node_id());
+        }
+
+        for header in &self.headers {
+            client.save_header(header).await?;
+        }
+        for block in &self.blocks {
+            client.save_side_block(block).await?;
+        }
+        for (tx_hash, block_hash) in &self.transactions {
+            client.index_tx(tx_hash, block_hash).await?;
+        }
+
+        if self.height > 1 {
+            client.set_pruned_height(self.height - 1).await?;
+        }
+        client.set_block_count(self.height).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::snapshot::*;
+    use crate::blockchain::transaction::{encode_transactions, Transaction};
+    use crate::blockchain::verify::verify;
+    use crate::storage::MemoryStore;
+
+    #[tokio::test]
+    async fn snapshot_test() -> Result<()> {
+        let store = MemoryStore::default();
+        let mut source = Client::memory("source".to_string(), store.clone());
+        let mut chain = Chain::with_client(source.clone()).await?;
+        let transaction = Transaction::new(1, b"paid".to_vec());
+        for timestamp in 1..=4 {
+            let data = encode_transactions(&[Transaction::new(timestamp, vec![])]);
+            chain.mine_next_block(timestamp * 10, data).await?;
+        }
+        let paid = encode_transactions(std::slice::from_ref(&transaction));
+        let paid_in = chain.mine_next_block(50, paid).await?;
+
+        let snapshot = Snapshot::create(&mut source, 5).await?;
+        assert_eq!(snapshot.blocks.len(), 3);
+        assert_eq!(snapshot.transactions.len(), 5);
+
+        let untrusted = snapshot.verify(Checkpoints::new(), &Checkpoints::new());
+        assert!(untrusted.unwrap_err().to_string().contains("trusted"));
+        let trusted = Checkpoints::from([(5, snapshot.commitment)]);
+        snapshot.verify(Checkpoints::new(), &trusted)?;
+
+        let mut tampered = snapshot.clone();
+        tampered.transactions.pop();
+        assert!(tampered.verify(Checkpoints::new(), &trusted).is_err());
+
+        // the commitment covers history below the tip too
+        let mut history = snapshot.headers.clone();
+        history[2].hash = [7; 32];
+        assert_ne!(
+            state_commitment(&history, &snapshot.transactions),
+            snapshot.commitment
+        );
+
+        let mut target = Client::memory("target".to_string(), store);
+        Chain::with_client(target.clone()).await?;
+        snapshot.restore(&mut target).await?;
+        assert!(snapshot.restore(&mut target).await.is_err());
+
+        let mut restored = Chain::with_client(target.clone()).await?;
+        assert_eq!(restored.hashes, chain.hashes);
+        assert_eq!(
+            target.get_tx_block(&transaction.hash()).await?,
+            paid_in.get_hash()
+        );
+        assert!(verify(&mut target, false).await?.is_valid());
+
+        let next = chain.mine_next_block(60, vec![]).await?;
+        assert_eq!(
+            restored.receive_block(&next).await?,
+            crate::blockchain::chain::BlockStatus::Extended
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{anyhow, bail, Result};
+use hex::decode;
+use std::collections::BTreeMap;
+
+use crate::blockchain::block::{BlockHash, BlockHeader};
+
+/// Block number -> expected block hash. Any header chain disagreeing with a
+/// checkpoint is rejected, so forks below a checkpoint are never followed.
+pub type Checkpoints = BTreeMap<usize, BlockHash>;
+
+/// Checkpoints shipped with the node, as `(block number, hex hash)`.
+pub const CHECKPOINTS: &[(usize, &str)] = &[];
+
+/// Parses `number:hash` pairs separated by commas, e.g. `10:0xab..,20:cd..`.
+pub fn parse_checkpoints(raw: &str) -> Result<Checkpoints> {
+    let mut checkpoints = Checkpoints::new();
+
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (number, hash) = entry
+            .split_once(':')
+            .ok_or_else(|| anyhow!("checkpoint `{}` is not `number:hash`", entry))?;
+        let number: usize = number.trim().parse()?;
+        let hash = decode(hash.trim().trim_start_matches("0x"))?;
+        let hash: BlockHash = hash
+            .try_into()
+            .map_err(|_| anyhow!("checkpoint hash at {} is not 32 bytes", number))?;
+
+        checkpoints.insert(number, hash);
+    }
+
+    Ok(checkpoints)
+}
+
+/// Hard-coded checkpoints plus configured `number:hash` entries.
+pub fn checkpoints(configured: &[String]) -> Result<Checkpoints> {
+    let hard_coded = CHECKPOINTS
+        .iter()
+        .map(|(number, hash)| format!("{}:{}", number, hash))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut checkpoints = parse_checkpoints(&hard_coded)?;
+
+    checkpoints.extend(parse_checkpoints(&configured.join(","))?);
+
+    Ok(checkpoints)
+}
+
+/// The best known chain of headers, built before any block body is fetched.
+#[derive(Default, Clone)]
+pub struct HeaderChain {
+    headers: Vec<BlockHeader>,
+    checkpoints: Checkpoints,
+}
+
+impl HeaderChain {
+    pub fn new(checkpoints: Checkpoints) -> Self {
+        HeaderChain {
+            headers: vec![],
+            checkpoints,
+        }
+    }
+
+    pub fn headers(&self) -> &[BlockHeader] {
+        &self.headers
+    }
+
+    pub fn tip(&self) -> Option<&BlockHeader> {
+        self.headers.last()
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn total_work(&self) -> u128 {
+        self.headers.iter().map(BlockHeader::work).sum()
+    }
+
+    fn position(&self, block_number: usize) -> Option<usize> {
+        let first = self.headers.first()?.block_number;
+        let index = block_number.checked_sub(first)?;
+
+        (index < self.headers.len()).then_some(index)
+    }
+
+    /// Checks PoW, numbering, linkage and checkpoints of `headers`, which
+    /// must either start a chain or extend ours from some height.
+    pub fn validate(&self, headers: &[BlockHeader]) -> Result<()> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+
+        let next_number = self.tip().map(|tip| tip.block_number + 1);
+        let mut prev = match self.position(first.block_number) {
+            _ if self.is_empty() => None,
+            Some(0) => None,
+            Some(index) => Some(self.headers[index - 1]),
+            None if next_number == Some(first.block_number) => self.tip().copied(),
+            None => bail!(
+                "header {} does not connect to the chain",
+                first.block_number
+            ),
+        };
+
+        for header in headers {
+            if !header.has_valid_pow() {
+                bail!("header {} has invalid proof of work", header.block_number);
+            }
+
+            if let Some(prev) = prev {
+                if header.block_number != prev.block_number + 1 {
+                    bail!("header {} is out of order", header.block_number);
+                }
+                if header.prev_hash != prev.hash {
+                    bail!("header {} does not link to its parent", header.block_number);
+                }
+            }
+
+            if let Some(expected) = self.checkpoints.get(&header.block_number) {
+                if *expected != header.hash {
+                    bail!("header {} does not match checkpoint", header.block_number);
+                }
+            }
+
+            prev = Some(*header);
+        }
+
+        Ok(())
+    }
+
+    /// Validates `headers` and makes them part of the best chain when they
+    /// carry more work than what they would replace. Returns whether the
+    /// best chain changed.
+    pub fn accept(&mut self, headers: Vec<BlockHeader>) -> Result<bool> {
+        self.validate(&headers)?;
+
+        let first = match headers.first() {
+            Some(first) => first.block_number,
+            None => return Ok(false),
+        };
+        let fork_index = self.position(first).unwrap_or(self.headers.len());
+
+        // a fork ending below a checkpoint would still drop the header there
+        let settled = self
+            .checkpoints
+            .keys()
+            .rev()
+            .find_map(|number| self.position(*number));
+        if settled.is_some_and(|index| fork_index <= index) {
+            bail!("headers from {} fork at or below a checkpoint", first);
+        }
+
+        let replaced_work: u128 = self.headers[fork_index..]
+            .iter()
+            .map(BlockHeader::work)
+            .sum();
+        let new_work: u128 = headers.iter().map(BlockHeader::work).sum();
+
+        if new_work <= replaced_work {
+            return Ok(false);
+        }
+
+        self.headers.truncate(fork_index);
+        self.headers.extend(headers);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::block::*;
+    use crate::blockchain::sync::*;
+
+    fn header(number: usize, prev_hash: BlockHash, seed: u8, difficulty: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            block_number: number,
+            difficulty,
+            data_root: Block::data_root(&[&number.to_be_bytes()[..], &[seed]].concat()),
+            prev_hash,
+            ..BlockHeader::default()
+        };
+        while !Block::meets_difficulty(&header.calculate_hash(), difficulty) {
+            header.nonce += 1;
+        }
+        header.hash = header.calculate_hash();
+
+        header
+    }
+
+    fn headers(from: usize, to: usize, prev_hash: BlockHash, seed: u8) -> Vec<BlockHeader> {
+        let mut prev_hash = prev_hash;
+        (from..=to)
+            .map(|number| {
+                let header = header(number, prev_hash, seed, 1);
+                prev_hash = header.hash;
+                header
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accept_linked_headers_test() -> Result<()> {
+        let mut chain = HeaderChain::default();
+        let main = headers(0, 5, [0; 32], 0);
+
+        assert!(chain.accept(main.clone())?);
+        assert_eq!(chain.len(), 6);
+        assert_eq!(chain.tip(), main.last());
+
+        let next = headers(6, 7, main[5].hash, 0);
+        assert!(chain.accept(next)?);
+        assert_eq!(chain.tip().unwrap().block_number, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_headers_test() {
+        let mut chain = HeaderChain::default();
+
+        let mut unlinked = headers(0, 3, [0; 32], 0);
+        unlinked[2].prev_hash = [9; 32];
+        assert!(chain.accept(unlinked).is_err());
+
+        let mut bad_pow = headers(0, 3, [0; 32], 0);
+        bad_pow[1].hash[0] = 255;
+        bad_pow[1].difficulty = 8;
+        assert!(chain.accept(bad_pow).is_err());
+
+        // a hash that meets any difficulty but is not the header's
+        let mut forged = headers(0, 3, [0; 32], 0);
+        forged[3].hash = [0; 32];
+        forged[3].difficulty = 127;
+        assert!(chain.accept(forged).is_err());
+
+        let disconnected = headers(10, 12, [0; 32], 0);
+        chain.accept(headers(0, 3, [0; 32], 0)).unwrap();
+        assert!(chain.accept(disconnected).is_err());
+    }
+
+    #[test]
+    fn best_chain_by_work_test() -> Result<()> {
+        let mut chain = HeaderChain::default();
+        let main = headers(0, 5, [0; 32], 0);
+        chain.accept(main.clone())?;
+
+        let short_fork = headers(3, 4, main[2].hash, 1);
+        assert!(!chain.accept(short_fork)?);
+        assert_eq!(chain.tip(), main.last());
+
+        let long_fork = headers(3, 7, main[2].hash, 1);
+        assert!(chain.accept(long_fork.clone())?);
+        assert_eq!(chain.tip(), long_fork.last());
+        assert_eq!(chain.headers()[2], main[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_rejects_fork_test() -> Result<()> {
+        let main = headers(0, 5, [0; 32], 0);
+        let mut checkpoints = Checkpoints::new();
+        checkpoints.insert(3, main[3].hash);
+
+        let mut chain = HeaderChain::new(checkpoints);
+        chain.accept(main.clone())?;
+
+        let fork = headers(2, 9, main[1].hash, 1);
+        assert!(chain.accept(fork).is_err());
+
+        // heavier than what it replaces, but stops before the checkpoint
+        let below = vec![header(2, main[1].hash, 1, 8)];
+        assert!(chain.accept(below).is_err());
+        assert_eq!(chain.headers()[3], main[3]);
+
+        let above = headers(4, 9, main[3].hash, 1);
+        assert!(chain.accept(above)?);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_checkpoints_test() -> Result<()> {
+        let hash = [7u8; 32];
+        let raw = format!("1:0x{}, 20:{}", hex::encode(hash), hex::encode(hash));
+        let checkpoints = parse_checkpoints(&raw)?;
+
+        assert_eq!(checkpoints.get(&1), Some(&hash));
+        assert_eq!(checkpoints.get(&20), Some(&hash));
+        assert!(parse_checkpoints("1:0x12").is_err());
+        assert!(parse_checkpoints("nope").is_err());
+        Ok(())
+    }
+}
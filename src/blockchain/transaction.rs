@@ -0,0 +1,99 @@
+use hex::encode;
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::blockchain::block::Block;
+
+pub type TxHash = [u8; 32];
+
+pub const MAX_TX_SIZE: usize = 64 * 1024;
+
+#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Transaction {
+    pub timestamp: u64,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("hash", &encode(self.hash()))
+            .field("timestamp", &self.timestamp)
+            .field("data", &self.data.to_vec())
+            .finish()
+    }
+}
+
+impl Transaction {
+    pub fn new(timestamp: u64, data: Vec<u8>) -> Self {
+        Transaction { timestamp, data }
+    }
+
+    pub fn hash(&self) -> TxHash {
+        Block::block_hash(&[&self.timestamp.to_be_bytes(), &self.data[..]].concat())
+    }
+
+    /// Whether the data is neither empty nor over `MAX_TX_SIZE` bytes.
+    pub fn has_valid_size(&self) -> bool {
+        !self.data.is_empty() && self.data.len() <= MAX_TX_SIZE
+    }
+}
+
+pub(crate) fn read<const N: usize>(data: &[u8], at: &mut usize) -> Option<[u8; N]> {
+    let bytes = data.get(*at..*at + N)?.try_into().ok()?;
+    *at += N;
+    Some(bytes)
+}
+
+/// Block data made of transactions: their count as a u32, then for each one
+/// its timestamp as a u64 and its data prefixed by its length as a u32.
+pub fn encode_transactions(transactions: &[Transaction]) -> Vec<u8> {
+    let mut data = (transactions.len() as u32).to_be_bytes().to_vec();
+
+    for transaction in transactions {
+        data.extend(transaction.timestamp.to_be_bytes());
+        data.extend((transaction.data.len() as u32).to_be_bytes());
+        data.extend(&transaction.data);
+    }
+
+    data
+}
+
+/// The transactions in some block data, `None` when the data is not
+/// exactly an `encode_transactions` output.
+pub fn decode_transactions(data: &[u8]) -> Option<Vec<Transaction>> {
+    let mut at = 0;
+    let count = u32::from_be_bytes(read(data, &mut at)?);
+    let mut transactions = vec![];
+
+    for _ in 0..count {
+        let timestamp = u64::from_be_bytes(read(data, &mut at)?);
+        let len = u32::from_be_bytes(read(data, &mut at)?) as usize;
+        let tx_data = data.get(at..at + len)?.to_vec();
+        at += len;
+
+        transactions.push(Transaction::new(timestamp, tx_data));
+    }
+
+    (at == data.len()).then_some(transactions)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::transaction::*;
+
+    #[test]
+    fn encode_decode_transactions_test() {
+        let transactions = vec![
+            Transaction::new(1, b"first".to_vec()),
+            Transaction::new(2, vec![]),
+        ];
+        let data = encode_transactions(&transactions);
+
+        assert_eq!(decode_transactions(&data), Some(transactions));
+        assert_eq!(decode_transactions(&encode_transactions(&[])), Some(vec![]));
+        assert_eq!(decode_transactions(&data[..data.len() - 1]), None);
+        assert_eq!(decode_transactions(b"opaque block data"), None);
+    }
+}
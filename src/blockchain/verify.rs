@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Result};
+use hex::encode;
+use std::fmt;
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::storage::{Client, NotFound};
+
+/// Something wrong with the stored main chain, by block number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// No number -> hash entry, though the count says there should be.
+    MissingIndex(usize),
+    /// The entry points at a block that is not stored.
+    MissingBlock(usize, BlockHash),
+    BadHash(usize),
+    BadPow(usize),
+    /// The block under this number says it has another one.
+    WrongNumber(usize, usize),
+    /// `prev_hash` is not the hash of the block indexed before it.
+    BrokenLink(usize),
+    WrongGenesis,
+    /// An entry past the block count.
+    BeyondCount(usize),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingIndex(number) => write!(f, "block {} is not indexed", number),
+            Issue::MissingBlock(number, hash) => {
+                write!(
+                    f,
+                    "block {} is indexed as 0x{}, which is not stored",
+                    number,
+                    encode(hash)
+                )
+            }
+            Issue::BadHash(number) => write!(f, "block {} does not hash to its hash", number),
+            Issue::BadPow(number) => write!(f, "block {} does not meet its difficulty", number),
+            Issue::WrongNumber(number, found) => {
+                write!(f, "block {} is numbered {}", number, found)
+            }
+            Issue::BrokenLink(number) => {
+                write!(f, "block {} does not point at block {}", number, number - 1)
+            }
+            Issue::WrongGenesis => write!(f, "block 0 is not the genesis block"),
+            Issue::BeyondCount(number) => write!(f, "block {} is indexed past the count", number),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// The stored block count, the tip number.
+    pub count: usize,
+    /// Blocks checked in full, including the ones indexed past the count.
+    pub checked: usize,
+    /// Blocks with a pruned body, only their header checked.
+    pub checked_headers_only: usize,
+    /// Last block of the valid chain from genesis.
+    pub valid_tip: Option<usize>,
+    pub issues: Vec<Issue>,
+    /// Whether the index and count were cut back to `valid_tip`.
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Walks the number index of the stored chain from genesis, recomputing
+/// every hash and proof of work and checking linkage, numbering and the
+/// block count. Where bodies are pruned only the headers are checked, and
+/// counted apart.
+/// With `repair`, the count is set back to the last block of the valid
+/// chain and index entries after it are dropped; block bodies are left
+/// where they are.
+pub async fn verify(client: &mut Client, repair: bool) -> Result<Report> {
+    let count = client.get_block_count().await;
+    let pruned_height = client.get_pruned_height().await;
+    let mut issues = vec![];
+    let mut valid_tip = None;
+    let mut prev_hash = None;
+    let mut indexed = vec![];
+    let mut headers_only = 0;
+
+    // entries past the count are found by listing the index, gaps and all
+    let beyond: Vec<usize> = client
+        .get_indexed_numbers()
+        .await?
+        .into_iter()
+        .filter(|number| *number > count)
+        .collect();
+
+    for number in (0..=count).chain(beyond) {
+        // nothing to link to across a gap
+        if indexed.last().map(|last| last + 1) != Some(number) {
+            prev_hash = None;
+        }
+        let hash = match client.get_hash_by_number(number).await {
+            Ok(hash) => hash,
+            Err(err) if err.is::<NotFound>() => {
+                issues.push(Issue::MissingIndex(number));
+                prev_hash = None;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        indexed.push(number);
+
+        let pruned = 0 < number && number < pruned_height;
+        let stored = match pruned {
+            true => client
+                .get_header_by_hash(&hash)
+                .await
+                .map(|header| (header, None)),
+            false => client
+                .get_block_by_hash(&hash)
+                .await
+                .map(|block| (block.header(), Some(block))),
+        };
+        let (header, body) = match stored {
+            Ok(stored) => stored,
+            Err(err) if err.is::<NotFound>() => {
+                issues.push(Issue::MissingBlock(number, hash));
+                prev_hash = None;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if pruned {
+            headers_only += 1;
+        }
+
+        let found = check_block(&header, body.as_ref(), number, hash, prev_hash);
+        let extends = found.is_empty() && valid_tip.map_or(number == 0, |tip| tip + 1 == number);
+        issues.extend(found);
+        if number > count {
+            issues.push(Issue::BeyondCount(number));
+        } else if extends {
+            valid_tip = Some(number);
+        }
+        prev_hash = Some(hash);
+    }
+
+    let mut report = Report {
+        count,
+        checked: indexed.len() - headers_only,
+        checked_headers_only: headers_only,
+        valid_tip,
+        issues,
+        repaired: false,
+    };
+
+    if repair && !report.is_valid() {
+        let tip = valid_tip.ok_or_else(|| anyhow!("no valid genesis block to keep"))?;
+        for number in indexed.into_iter().filter(|number| *number > tip) {
+            client.unindex_block(number).await?;
+        }
+        client.set_block_count(tip).await?;
+        report.repaired = true;
+    }
+
+    Ok(report)
+}
+
+/// Checks a block, or only its header when the body is pruned.
+fn check_block(
+    header: &BlockHeader,
+    body: Option<&Block>,
+    number: usize,
+    hash: BlockHash,
+    prev_hash: Option<BlockHash>,
+) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    if number == 0 {
+        if hash != Block::genesis().get_hash() || body != Some(&Block::genesis()) {
+            issues.push(Issue::WrongGenesis);
+        }
+        return issues;
+    }
+
+    let computed = match body {
+        Some(block) => Block::calculate_hash(
+            block.get_timestamp(),
+            block.get_data(),
+            &block.get_prev_hash(),
+            block.get_difficulty(),
+            block.get_nonce(),
+        ),
+        None => header.calculate_hash(),
+    };
+    if computed != header.hash || computed != hash {
+        issues.push(Issue::BadHash(number));
+    }
+    if !header.has_valid_pow() {
+        issues.push(Issue::BadPow(number));
+    }
+    if header.block_number != number {
+        issues.push(Issue::WrongNumber(number, header.block_number));
+    }
+    // without the block before, already reported, there is nothing to link to
+    if prev_hash.is_some_and(|prev_hash| prev_hash != header.prev_hash) {
+        issues.push(Issue::BrokenLink(number));
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::chain::Chain;
+    use crate::blockchain::verify::*;
+    use crate::storage::MemoryStore;
+
+    async fn mined(blocks: u64) -> Result<(Client, Chain)> {
+        let client = Client::memory("0".to_string(), MemoryStore::default());
+        let mut chain = Chain::with_client(client.clone()).await?;
+        for timestamp in 1..=blocks {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        Ok((client, chain))
+    }
+
+    #[tokio::test]
+    async fn valid_chain_test() -> Result<()> {
+        let (mut client, _) = mined(3).await?;
+        let report = verify(&mut client, true).await?;
+
+        assert!(report.is_valid());
+        assert_eq!((report.count, report.checked), (3, 4));
+        assert_eq!(report.checked_headers_only, 0);
+        assert_eq!(report.valid_tip, Some(3));
+        assert!(!report.repaired);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repair_test() -> Result<()> {
+        let (mut client, mut chain) = mined(4).await?;
+        let second = chain.get_block_by_chain_index(2).await?;
+        client.delete_block(&second.get_hash()).await?;
+        client.set_block_count(3).await?;
+
+        let report = verify(&mut client, false).await?;
+        assert_eq!(
+            report.issues,
+            vec![Issue::MissingIndex(2), Issue::BeyondCount(4)]
+        );
+        assert_eq!(report.valid_tip, Some(1));
+
+        let report = verify(&mut client, true).await?;
+        assert!(report.repaired);
+        assert_eq!(client.get_block_count().await, 1);
+        assert!(verify(&mut client, false).await?.is_valid());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tampered_block_test() -> Result<()> {
+        let (mut client, mut chain) = mined(2).await?;
+        let mut block = chain.get_block_by_chain_index(2).await?;
+        block.nonce += 1;
+        client.save_side_block(&block).await?;
+
+        let report = verify(&mut client, false).await?;
+        assert!(report.issues.contains(&Issue::BadHash(2)));
+        assert_eq!(report.valid_tip, Some(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn beyond_gap_test() -> Result<()> {
+        let (mut client, mut chain) = mined(6).await?;
+        let fifth = chain.get_block_by_chain_index(5).await?;
+        client.delete_block(&fifth.get_hash()).await?;
+        client.set_block_count(3).await?;
+
+        let report = verify(&mut client, true).await?;
+        assert_eq!(
+            report.issues,
+            vec![Issue::BeyondCount(4), Issue::BeyondCount(6)]
+        );
+        assert_eq!(client.get_indexed_numbers().await?, vec![0, 1, 2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pruned_headers_test() -> Result<()> {
+        let (mut client, mut chain) = mined(5).await?;
+        chain.set_prune(Some(2));
+        chain.prune().await?;
+
+        let report = verify(&mut client, false).await?;
+        assert!(report.is_valid());
+        assert_eq!((report.checked, report.checked_headers_only), (3, 3));
+
+        let mut header = client.get_header_by_number(2).await?;
+        header.data_root = [1; 32];
+        client.save_header(&header).await?;
+        let report = verify(&mut client, false).await?;
+        assert!(report.issues.contains(&Issue::BadHash(2)));
+        Ok(())
+    }
+}
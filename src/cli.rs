@@ -0,0 +1,615 @@
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use ed25519_dalek::SigningKey;
+use hex::{decode, encode};
+use rand::rngs::OsRng;
+use rocket::serde::json::serde_json::{to_string, to_string_pretty};
+use rocket::serde::json::{from_str, json, Value};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::archive::{ArchiveHeader, ArchiveReader, ArchiveWriter, Format};
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::chain::{BlockStatus, Chain};
+use crate::blockchain::snapshot::Snapshot;
+use crate::blockchain::transaction::{encode_transactions, Transaction};
+use crate::blockchain::verify::verify;
+use crate::config::{ConfigLayer, NodeConfig};
+use crate::devnet::{self, DevnetOptions, DEVNET_BASE_PORT, DEVNET_DIR};
+use crate::network::node::unix_now;
+use crate::storage::Client;
+
+/// Full blockchain node and tools working on its storage.
+#[derive(Debug, Parser)]
+#[command(name = "full_blockchain", version)]
+pub struct Cli {
+    /// TOML config file, `node.toml` when present.
+    #[arg(long, global = true, env = "CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Node whose data to use, overrides `NODE_ID`.
+    #[arg(long, global = true)]
+    pub node_id: Option<String>,
+
+    /// Redis URL, overrides `DB`.
+    #[arg(long, global = true)]
+    pub db: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the node.
+    #[command(subcommand)]
+    Node(NodeCommand),
+    /// Mine blocks on the local chain without starting the node.
+    Mine {
+        #[arg(long, default_value_t = 1)]
+        blocks: usize,
+    },
+    /// Read blocks from storage.
+    #[command(subcommand)]
+    Block(BlockCommand),
+    /// Check or move the whole chain.
+    #[command(subcommand)]
+    Chain(ChainCommand),
+    /// Manage key pairs.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Show the peers in the address book.
+    #[command(subcommand)]
+    Peers(PeersCommand),
+    /// List or drop the data of the nodes sharing the database.
+    #[command(subcommand)]
+    Storage(StorageCommand),
+    /// Run several nodes wired to each other, for tests and demos.
+    Devnet {
+        #[arg(long, default_value_t = 3)]
+        nodes: usize,
+        /// How many of the nodes keep mining.
+        #[arg(long, default_value_t = 0)]
+        miners: usize,
+        /// First of the three ports each node takes.
+        #[arg(long, default_value_t = DEVNET_BASE_PORT)]
+        base_port: u16,
+        /// Run nodes as child processes on Redis instead of in memory here.
+        #[arg(long)]
+        processes: bool,
+        /// Config and log directory of each child process.
+        #[arg(long, default_value = DEVNET_DIR)]
+        dir: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum NodeCommand {
+    /// Sync, then serve the API and talk to peers.
+    Run {
+        /// Peer to peer listen address, overrides `P2P_ADDR`.
+        #[arg(long)]
+        p2p_addr: Option<SocketAddr>,
+        /// Comma separated peers to dial at start, overrides `PEERS`.
+        #[arg(long, value_delimiter = ',')]
+        peers: Option<Vec<SocketAddr>>,
+        /// WebSocket listen address, overrides `WS_ADDR`.
+        #[arg(long)]
+        ws_addr: Option<SocketAddr>,
+        /// HTTP API listen address, overrides `HTTP_ADDR`.
+        #[arg(long)]
+        http_addr: Option<SocketAddr>,
+        /// Keep mining blocks, overrides `MINE`.
+        #[arg(long, overrides_with = "no_mine")]
+        mine: bool,
+        /// Don't mine, overrides `MINE`.
+        #[arg(long, overrides_with = "mine")]
+        no_mine: bool,
+        /// Keep only this many of the last block bodies, overrides `PRUNE`.
+        /// 0 keeps them all.
+        #[arg(long)]
+        prune: Option<usize>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BlockCommand {
+    /// Print a block as JSON.
+    Get {
+        /// Block number, or a 0x prefixed or 64 digit hash.
+        id: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ChainCommand {
+    /// Check the stored chain from genesis: hashes, proof of work, links,
+    /// the number index and the block count.
+    Verify {
+        /// Cut the index and count back to the last valid block.
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Write main chain blocks to a file.
+    Export {
+        path: PathBuf,
+        /// First block number to write.
+        #[arg(long, default_value_t = 0)]
+        from: usize,
+        /// Last block number to write, the tip by default.
+        #[arg(long)]
+        to: Option<usize>,
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+    },
+    /// Validate and add the blocks of an export, in either format.
+    Import { path: PathBuf },
+    /// Start a chain from a snapshot, or take one to start others from.
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    /// Write a snapshot of the chain at a height.
+    Create {
+        path: PathBuf,
+        /// The tip by default.
+        #[arg(long)]
+        height: Option<usize>,
+    },
+    /// Check a snapshot against the checkpoints and trusted commitments,
+    /// then make it the chain of an empty node.
+    Load { path: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// Generate an ed25519 key pair.
+    New {
+        /// File to write the key pair to instead of printing it.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PeersCommand {
+    /// List the saved address book.
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StorageCommand {
+    /// List the nodes with data in the database.
+    Namespaces,
+    /// Delete every key of the node.
+    Drop {
+        /// Confirm deleting the node's chain, index and peers.
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+impl Cli {
+    /// The configuration set by flags, which wins over every other source.
+    pub fn overrides(&self) -> ConfigLayer {
+        let mut layer = ConfigLayer {
+            node_id: self.node_id.clone(),
+            db: self.db.clone(),
+            ..ConfigLayer::default()
+        };
+
+        if let Command::Node(NodeCommand::Run {
+            p2p_addr,
+            peers,
+            ws_addr,
+            http_addr,
+            mine,
+            no_mine,
+            prune,
+        }) = &self.command
+        {
+            layer.p2p_addr = *p2p_addr;
+            layer.peers = peers.clone();
+            layer.ws_addr = *ws_addr;
+            layer.http_addr = *http_addr;
+            layer.mine = match (mine, no_mine) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            layer.prune = *prune;
+        }
+
+        layer
+    }
+}
+
+/// Runs every command but `node run`, which needs the server.
+pub async fn run(command: Command, config: &NodeConfig) -> Result<()> {
+    match command {
+        Command::Node(_) => Err(anyhow!("node commands are run by the binary")),
+        Command::Mine { blocks } => {
+            for block in mine(
+                Client::from_config(config).await?,
+                config.block_time,
+                blocks,
+            )
+            .await?
+            {
+                println!(
+                    "MINED BLOCK {}: 0x{}",
+                    block.get_block_number(),
+                    encode(block.get_hash())
+                );
+            }
+            Ok(())
+        }
+        Command::Block(BlockCommand::Get { id }) => {
+            let block = get_block(Client::from_config(config).await?, &id).await?;
+            println!("{}", to_string_pretty(&block)?);
+            Ok(())
+        }
+        Command::Chain(ChainCommand::Verify { repair }) => {
+            let report = verify(&mut Client::from_config(config).await?, repair).await?;
+            for issue in &report.issues {
+                println!("CHAIN ISSUE: {}", issue);
+            }
+
+            match (report.is_valid(), report.repaired, report.valid_tip) {
+                (true, _, _) => println!(
+                    "CHAIN VALID: {} BLOCKS, {} MORE PRUNED AND CHECKED BY HEADER",
+                    report.checked, report.checked_headers_only
+                ),
+                (false, true, Some(tip)) => println!("CHAIN REPAIRED: TIP IS BLOCK {}", tip),
+                _ => {
+                    return Err(anyhow!(
+                        "chain has {} issues, run with --repair to fix the index",
+                        report.issues.len()
+                    ))
+                }
+            }
+            Ok(())
+        }
+        Command::Chain(ChainCommand::Export {
+            path,
+            from,
+            to,
+            format,
+        }) => {
+            let client = Client::from_config(config).await?;
+            let count = export(client, &path, from, to, format).await?;
+            println!("EXPORTED {} BLOCKS TO {}", count, path.display());
+            Ok(())
+        }
+        Command::Chain(ChainCommand::Import { path }) => {
+            let count = import(Client::from_config(config).await?, &path).await?;
+            println!("IMPORTED {} BLOCKS FROM {}", count, path.display());
+            Ok(())
+        }
+        Command::Chain(ChainCommand::Snapshot(SnapshotCommand::Create { path, height })) => {
+            let mut client = Client::from_config(config).await?;
+            let height = match height {
+                Some(height) => height,
+                None => client.get_block_count().await,
+            };
+            let snapshot = Snapshot::create(&mut client, height).await?;
+            fs::write(&path, to_string(&snapshot)?)?;
+            println!(
+                "SNAPSHOT AT BLOCK {}: COMMITMENT {}:0x{}",
+                height,
+                height,
+                encode(snapshot.commitment)
+            );
+            Ok(())
+        }
+        Command::Chain(ChainCommand::Snapshot(SnapshotCommand::Load { path })) => {
+            let snapshot: Snapshot = from_str(&fs::read_to_string(&path)?)?;
+            snapshot.verify(config.checkpoints()?, &config.snapshot_checkpoints()?)?;
+            snapshot
+                .restore(&mut Client::from_config(config).await?)
+                .await?;
+            println!("CHAIN STARTS FROM SNAPSHOT AT BLOCK {}", snapshot.height);
+            Ok(())
+        }
+        Command::Keys(KeysCommand::New { out }) => {
+            let keys = new_keys();
+            match out {
+                Some(path) => {
+                    write_private(&path, &to_string_pretty(&keys)?)?;
+                    println!("PUBLIC KEY {}", keys["public"].as_str().unwrap_or_default());
+                }
+                None => println!("{}", to_string_pretty(&keys)?),
+            }
+            Ok(())
+        }
+        Command::Peers(PeersCommand::List) => {
+            let mut client = Client::from_config(config).await?;
+            for peer in client.get_peers().await.unwrap_or_default() {
+                println!(
+                    "{} last seen {} banned until {}",
+                    peer.address, peer.last_seen, peer.banned_until
+                );
+            }
+            Ok(())
+        }
+        Command::Storage(StorageCommand::Namespaces) => {
+            let mut client = Client::from_config(config).await?;
+            for namespace in client.list_namespaces().await? {
+                println!("{}", namespace.name());
+            }
+            Ok(())
+        }
+        Command::Storage(StorageCommand::Drop { yes }) => {
+            if !yes {
+                return Err(anyhow!(
+                    "this deletes all data of node {}, run with --yes to go ahead",
+                    config.node_id
+                ));
+            }
+            let dropped = Client::from_config(config).await?.drop_namespace().await?;
+            println!("DROPPED {} KEYS OF NODE {}", dropped, config.node_id);
+            Ok(())
+        }
+        Command::Devnet {
+            nodes,
+            miners,
+            base_port,
+            processes,
+            dir,
+        } => {
+            let options = DevnetOptions {
+                nodes,
+                miners,
+                base_port,
+                processes,
+                dir,
+            };
+            devnet::run(config, options).await
+        }
+    }
+}
+
+/// Mines `blocks` blocks with only a coinbase transaction.
+pub async fn mine(client: Client, block_time: u32, blocks: usize) -> Result<Vec<Block>> {
+    let node_id = client.node_id();
+    let mut chain = Chain::with_client(client).await?;
+    chain.set_block_time(block_time);
+    let mut mined = vec![];
+
+    for _ in 0..blocks {
+        let now = unix_now();
+        let coinbase = Transaction::new(now, format!("mined by node {}", node_id).into_bytes());
+        mined.push(
+            chain
+                .mine_next_block(now, encode_transactions(&[coinbase]))
+                .await?,
+        );
+    }
+
+    Ok(mined)
+}
+
+pub async fn get_block(mut client: Client, id: &str) -> Result<Block> {
+    if id.starts_with("0x") || id.len() == 64 {
+        let hash: BlockHash = decode(id.trim_start_matches("0x"))?
+            .try_into()
+            .map_err(|_| anyhow!("a block hash is 32 bytes"))?;
+        return client.get_block_by_hash(&hash).await;
+    }
+
+    client.get_block_by_number(id.parse()?).await
+}
+
+/// Writes the main chain blocks `from..=to`, returns how many.
+pub async fn export(
+    client: Client,
+    path: &Path,
+    from: usize,
+    to: Option<usize>,
+    format: Format,
+) -> Result<usize> {
+    let mut chain = Chain::with_client(client).await?;
+    let tip = chain.hashes.len() - 1;
+    let to = to.unwrap_or(tip);
+    if from > to || to > tip {
+        return Err(anyhow!(
+            "blocks {} to {} are not in a chain up to {}",
+            from,
+            to,
+            tip
+        ));
+    }
+
+    let header = ArchiveHeader::new(chain.hashes[0], (to - from + 1) as u64);
+    let mut archive = ArchiveWriter::new(BufWriter::new(File::create(path)?), format, header)?;
+    for number in from..=to {
+        archive.write(&chain.get_block_by_chain_index(number).await?)?;
+    }
+    archive.finish()?;
+
+    Ok(to - from + 1)
+}
+
+/// Feeds the blocks to the chain as if peers had sent them, so each one is
+/// validated before it is stored, and returns how many extended it. Every
+/// block has to connect to the local chain.
+pub async fn import(client: Client, path: &Path) -> Result<usize> {
+    let mut chain = Chain::with_client(client).await?;
+    let archive = ArchiveReader::new(BufReader::new(File::open(path)?))?;
+    if let Some(header) = archive.header() {
+        if header.genesis != chain.hashes[0] {
+            return Err(anyhow!(
+                "export is of another chain, with genesis 0x{}",
+                encode(header.genesis)
+            ));
+        }
+    }
+
+    let mut imported = 0;
+    for block in archive {
+        let block = block?;
+        match chain.receive_block(&block).await? {
+            BlockStatus::Extended | BlockStatus::Reorganized => imported += 1,
+            BlockStatus::Invalid => {
+                return Err(anyhow!("block {} is invalid", block.get_block_number()))
+            }
+            BlockStatus::Orphan => {
+                return Err(anyhow!(
+                    "block {} does not connect to the local chain",
+                    block.get_block_number()
+                ))
+            }
+            BlockStatus::Known | BlockStatus::SideChain => {}
+        }
+    }
+
+    Ok(imported)
+}
+
+/// Creates a file only its owner can read, never replacing one.
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options
+        .open(path)
+        .map_err(|err| anyhow!("could not create {}: {}", path.display(), err))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+pub fn new_keys() -> Value {
+    let key = SigningKey::generate(&mut OsRng);
+
+    json!({
+        "public": encode(key.verifying_key().to_bytes()),
+        "secret": encode(key.to_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cli::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn parse_test() {
+        let cli = Cli::try_parse_from([
+            "full_blockchain",
+            "node",
+            "run",
+            "--http-addr",
+            "127.0.0.1:8010",
+            "--peers",
+            "127.0.0.1:7001,127.0.0.1:7002",
+            "--node-id",
+            "3",
+        ])
+        .unwrap();
+        let layer = cli.overrides();
+        assert_eq!(layer.node_id, Some("3".to_string()));
+        assert_eq!(layer.http_addr, Some("127.0.0.1:8010".parse().unwrap()));
+        assert_eq!(layer.peers.unwrap().len(), 2);
+        assert_eq!(layer.db, None);
+        assert_eq!(layer.mine, None);
+
+        let mine = |args: &[&str]| {
+            let args = ["full_blockchain", "node", "run"].iter().chain(args);
+            Cli::try_parse_from(args).unwrap().overrides().mine
+        };
+        assert_eq!(mine(&["--mine"]), Some(true));
+        assert_eq!(mine(&["--no-mine"]), Some(false));
+        assert_eq!(mine(&["--mine", "--no-mine"]), Some(false));
+
+        let cli = Cli::try_parse_from(["full_blockchain", "mine", "--blocks", "4"]).unwrap();
+        assert!(matches!(cli.command, Command::Mine { blocks: 4 }));
+        assert!(Cli::try_parse_from(["full_blockchain", "chain", "export"]).is_err());
+        let cli = Cli::try_parse_from([
+            "full_blockchain",
+            "chain",
+            "export",
+            "blocks.bin",
+            "--format",
+            "binary",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Chain(ChainCommand::Export {
+                format: Format::Binary,
+                to: None,
+                ..
+            })
+        ));
+        assert!(Cli::try_parse_from(["full_blockchain", "mine", "--blocks", "x"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn export_import_test() -> Result<()> {
+        let store = MemoryStore::default();
+        let source = Client::memory("source".to_string(), store.clone());
+        let mut chain = Chain::with_client(source.clone()).await?;
+        for timestamp in 1..=3 {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        let path = std::env::temp_dir().join(format!("export_import_{}.jsonl", unix_now()));
+        assert_eq!(
+            export(source.clone(), &path, 0, None, Format::Jsonl).await?,
+            4
+        );
+
+        let target = Client::memory("target".to_string(), store.clone());
+        assert_eq!(import(target.clone(), &path).await?, 3);
+        fs::remove_file(&path)?;
+
+        let path = std::env::temp_dir().join(format!("export_import_{}.bin", unix_now()));
+        assert_eq!(
+            export(source.clone(), &path, 2, Some(3), Format::Binary).await?,
+            2
+        );
+        let behind = Client::memory("behind".to_string(), store);
+        let err = import(behind, &path).await.unwrap_err();
+        assert!(err.to_string().contains("does not connect"));
+        fs::remove_file(&path)?;
+        assert!(export(source.clone(), &path, 2, Some(9), Format::Binary)
+            .await
+            .is_err());
+
+        let tip = get_block(target.clone(), "3").await?;
+        assert_eq!(tip, chain.get_last_block().await?);
+        let by_hash = get_block(target, &format!("0x{}", encode(tip.get_hash()))).await?;
+        assert_eq!(by_hash, tip);
+        Ok(())
+    }
+
+    #[test]
+    fn write_private_test() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("keys_{}.json", unix_now()));
+        write_private(&path, "secret")?;
+        assert!(write_private(&path, "other").is_err());
+        assert_eq!(fs::read_to_string(&path)?, "secret");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn new_keys_test() {
+        let keys = new_keys();
+
+        assert_eq!(keys["public"].as_str().unwrap().len(), 64);
+        assert_ne!(keys["secret"], new_keys()["secret"]);
+    }
+}
@@ -0,0 +1,327 @@
+use anyhow::{anyhow, bail, Context, Result};
+use rocket::serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::chain::{BLOCK_TIME, MIN_PRUNE_KEEP};
+use crate::blockchain::sync::{checkpoints, parse_checkpoints, Checkpoints};
+use crate::network::node::DEFAULT_P2P_PORT;
+use crate::server::ws::DEFAULT_WS_ADDR;
+use crate::storage::cache::DEFAULT_CACHE_SIZE;
+use crate::storage::DB_ENDPOINT;
+
+/// Read when neither `--config` nor `CONFIG` name a file and it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "node.toml";
+pub const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:8000";
+
+/// Everything a node is configured with. Built by `NodeConfig::load` from
+/// defaults, a TOML file, environment variables and command-line flags,
+/// each overriding the ones before.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NodeConfig {
+    pub node_id: String,
+    /// Redis URL.
+    pub db: String,
+    pub p2p_addr: SocketAddr,
+    /// Peers dialed at start.
+    pub peers: Vec<SocketAddr>,
+    pub http_addr: SocketAddr,
+    pub ws_addr: SocketAddr,
+    /// Target milliseconds between blocks.
+    pub block_time: u32,
+    /// `number:hash` checkpoints on top of the hard-coded ones.
+    pub checkpoints: Vec<String>,
+    /// Mine a block every `block_time` on top of serving.
+    pub mine: bool,
+    /// `number:commitment` of the snapshots a node may start from.
+    pub snapshot_checkpoints: Vec<String>,
+    /// Keep only this many of the last block bodies, all when `None`.
+    pub prune: Option<usize>,
+    /// Blocks, headers and hashes each kept in memory, none when 0.
+    pub cache_size: usize,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            node_id: "0".to_string(),
+            db: DB_ENDPOINT.to_string(),
+            p2p_addr: SocketAddr::from(([127, 0, 0, 1], DEFAULT_P2P_PORT)),
+            peers: vec![],
+            http_addr: DEFAULT_HTTP_ADDR.parse().unwrap(),
+            ws_addr: DEFAULT_WS_ADDR.parse().unwrap(),
+            block_time: BLOCK_TIME,
+            checkpoints: vec![],
+            mine: false,
+            snapshot_checkpoints: vec![],
+            prune: None,
+            cache_size: DEFAULT_CACHE_SIZE,
+        }
+    }
+}
+
+/// One layer of configuration, only what it sets is `Some`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde", deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub node_id: Option<String>,
+    pub db: Option<String>,
+    pub p2p_addr: Option<SocketAddr>,
+    pub peers: Option<Vec<SocketAddr>>,
+    pub http_addr: Option<SocketAddr>,
+    pub ws_addr: Option<SocketAddr>,
+    pub block_time: Option<u32>,
+    pub checkpoints: Option<Vec<String>>,
+    pub mine: Option<bool>,
+    pub snapshot_checkpoints: Option<Vec<String>>,
+    /// 0 turns pruning off again, whatever the layers below said.
+    pub prune: Option<usize>,
+    pub cache_size: Option<usize>,
+}
+
+impl ConfigLayer {
+    pub fn from_toml(raw: &str) -> Result<Self> {
+        Ok(toml::from_str(raw)?)
+    }
+
+    /// Reads `NODE_ID`, `DB`, `P2P_ADDR`, `PEERS`, `HTTP_ADDR`, `WS_ADDR`,
+    /// `BLOCK_TIME`, `CHECKPOINTS`, `MINE`, `SNAPSHOT_CHECKPOINTS`, `PRUNE`
+    /// and `CACHE_SIZE`, from the process or `.env`. Lists are comma
+    /// separated, `PRUNE=0` keeps every block body.
+    pub fn from_env() -> Result<Self> {
+        let var = |key: &str| dotenv::var(key).ok().map(|value| value.trim().to_string());
+        let parse = |key: &str| -> Result<Option<SocketAddr>> {
+            var(key)
+                .map(|value| value.parse().with_context(|| invalid(key, &value)))
+                .transpose()
+        };
+
+        Ok(ConfigLayer {
+            node_id: var("NODE_ID"),
+            db: var("DB"),
+            p2p_addr: parse("P2P_ADDR")?,
+            peers: var("PEERS")
+                .map(|value| {
+                    split_list(&value)
+                        .map(|addr| addr.parse().with_context(|| invalid("PEERS", addr)))
+                        .collect()
+                })
+                .transpose()?,
+            http_addr: parse("HTTP_ADDR")?,
+            ws_addr: parse("WS_ADDR")?,
+            block_time: var("BLOCK_TIME")
+                .map(|value| value.parse().with_context(|| invalid("BLOCK_TIME", &value)))
+                .transpose()?,
+            checkpoints: var("CHECKPOINTS")
+                .map(|value| split_list(&value).map(str::to_string).collect()),
+            snapshot_checkpoints: var("SNAPSHOT_CHECKPOINTS")
+                .map(|value| split_list(&value).map(str::to_string).collect()),
+            prune: var("PRUNE")
+                .map(|value| value.parse().with_context(|| invalid("PRUNE", &value)))
+                .transpose()?,
+            cache_size: var("CACHE_SIZE")
+                .map(|value| value.parse().with_context(|| invalid("CACHE_SIZE", &value)))
+                .transpose()?,
+            mine: var("MINE")
+                .map(|value| value.parse().with_context(|| invalid("MINE", &value)))
+                .transpose()?,
+        })
+    }
+}
+
+fn invalid(key: &str, value: &str) -> String {
+    format!("invalid {} {:?}", key, value)
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+impl NodeConfig {
+    /// Defaults, then the TOML file, then the environment, then `flags`.
+    /// The file is `path`, else `CONFIG`, else `node.toml` when present.
+    pub fn load(path: Option<&Path>, flags: ConfigLayer) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path.to_path_buf()),
+            None => dotenv::var("CONFIG").ok().map(PathBuf::from),
+        };
+
+        let mut config = NodeConfig::default();
+        match path {
+            Some(path) => config.merge(read_file(&path)?),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                config.merge(read_file(Path::new(DEFAULT_CONFIG_FILE))?)
+            }
+            None => {}
+        }
+        config.merge(ConfigLayer::from_env()?);
+        config.merge(flags);
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn merge(&mut self, layer: ConfigLayer) {
+        let ConfigLayer {
+            node_id,
+            db,
+            p2p_addr,
+            peers,
+            http_addr,
+            ws_addr,
+            block_time,
+            checkpoints,
+            mine,
+            snapshot_checkpoints,
+            prune,
+            cache_size,
+        } = layer;
+
+        self.node_id = node_id.unwrap_or(self.node_id.clone());
+        self.db = db.unwrap_or(self.db.clone());
+        self.p2p_addr = p2p_addr.unwrap_or(self.p2p_addr);
+        self.peers = peers.unwrap_or(self.peers.clone());
+        self.http_addr = http_addr.unwrap_or(self.http_addr);
+        self.ws_addr = ws_addr.unwrap_or(self.ws_addr);
+        self.block_time = block_time.unwrap_or(self.block_time);
+        self.checkpoints = checkpoints.unwrap_or(self.checkpoints.clone());
+        self.mine = mine.unwrap_or(self.mine);
+        self.snapshot_checkpoints =
+            snapshot_checkpoints.unwrap_or(self.snapshot_checkpoints.clone());
+        self.prune = match prune {
+            Some(0) => None,
+            Some(keep) => Some(keep),
+            None => self.prune,
+        };
+        self.cache_size = cache_size.unwrap_or(self.cache_size);
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let id_chars = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.node_id.is_empty() || !self.node_id.chars().all(id_chars) {
+            bail!(
+                "node_id {:?} must be letters, digits, '-' or '_'",
+                self.node_id
+            );
+        }
+        if !self.db.starts_with("redis://") && !self.db.starts_with("rediss://") {
+            bail!("db {:?} is not a redis:// URL", self.db);
+        }
+        if self.block_time == 0 {
+            bail!("block_time must be more than 0 milliseconds");
+        }
+        if self.prune.is_some_and(|keep| keep < MIN_PRUNE_KEEP) {
+            bail!("prune must keep at least {} blocks", MIN_PRUNE_KEEP);
+        }
+
+        let listeners = [
+            ("p2p_addr", self.p2p_addr),
+            ("http_addr", self.http_addr),
+            ("ws_addr", self.ws_addr),
+        ];
+        for (i, (name, addr)) in listeners.iter().enumerate() {
+            for (other, other_addr) in &listeners[i + 1..] {
+                if addr == other_addr && addr.port() != 0 {
+                    bail!("{} and {} are both {}", name, other, addr);
+                }
+            }
+        }
+        if self.peers.contains(&self.p2p_addr) {
+            bail!("peers include this node's own p2p_addr {}", self.p2p_addr);
+        }
+
+        self.checkpoints()?;
+        self.snapshot_checkpoints()?;
+        Ok(())
+    }
+
+    /// Hard-coded and configured checkpoints.
+    pub fn checkpoints(&self) -> Result<Checkpoints> {
+        checkpoints(&self.checkpoints).map_err(|err| anyhow!("invalid checkpoints: {}", err))
+    }
+
+    /// Commitments of the snapshots trusted at their heights.
+    pub fn snapshot_checkpoints(&self) -> Result<Checkpoints> {
+        parse_checkpoints(&self.snapshot_checkpoints.join(","))
+            .map_err(|err| anyhow!("invalid snapshot_checkpoints: {}", err))
+    }
+}
+
+fn read_file(path: &Path) -> Result<ConfigLayer> {
+    let raw = fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+
+    ConfigLayer::from_toml(&raw).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+
+    #[test]
+    fn layers_test() {
+        let file = ConfigLayer::from_toml(
+            r#"
+            node_id = "file"
+            peers = ["127.0.0.1:7001", "127.0.0.1:7002"]
+            block_time = 10000
+            cache_size = 16
+            "#,
+        )
+        .unwrap();
+        let flags = ConfigLayer {
+            node_id: Some("flag".to_string()),
+            ..ConfigLayer::default()
+        };
+
+        let mut config = NodeConfig::default();
+        config.merge(file);
+        config.merge(flags);
+
+        assert_eq!(config.node_id, "flag");
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.block_time, 10000);
+        assert_eq!(config.cache_size, 16);
+        assert_eq!(config.db, DB_ENDPOINT);
+        assert!(config.validate().is_ok());
+
+        config.merge(ConfigLayer::from_toml("prune = 20").unwrap());
+        assert_eq!(config.prune, Some(20));
+        config.merge(ConfigLayer::default());
+        assert_eq!(config.prune, Some(20));
+        config.merge(ConfigLayer {
+            prune: Some(0),
+            ..ConfigLayer::default()
+        });
+        assert_eq!(config.prune, None);
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        assert!(ConfigLayer::from_toml("node_idd = \"typo\"").is_err());
+        assert!(ConfigLayer::from_toml("p2p_addr = \"not an address\"").is_err());
+
+        let bad = |change: fn(&mut NodeConfig)| {
+            let mut config = NodeConfig::default();
+            change(&mut config);
+            config.validate().unwrap_err().to_string()
+        };
+        assert!(bad(|config| config.node_id = "a::b".to_string()).contains("node_id"));
+        assert!(bad(|config| config.db = "localhost".to_string()).contains("redis://"));
+        assert!(bad(|config| config.block_time = 0).contains("block_time"));
+        assert!(bad(|config| config.prune = Some(1)).contains("prune"));
+        assert!(bad(|config| config.ws_addr = config.http_addr).contains("ws_addr"));
+        assert!(bad(|config| config.peers = vec![config.p2p_addr]).contains("own"));
+        assert!(
+            bad(|config| config.checkpoints = vec!["1:0x12".to_string()]).contains("checkpoint")
+        );
+        assert!(
+            bad(|config| config.snapshot_checkpoints = vec!["x".to_string()]).contains("snapshot")
+        );
+    }
+}
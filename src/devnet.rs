@@ -0,0 +1,233 @@
+use anyhow::{anyhow, bail, Result};
+use futures_util::future::{select_all, try_join_all};
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::{Child, Command};
+
+use crate::config::{NodeConfig, DEFAULT_CONFIG_FILE};
+use crate::server;
+use crate::storage::{Client, MemoryStore};
+
+pub const DEVNET_BASE_PORT: u16 = 9100;
+pub const DEVNET_DIR: &str = "devnet";
+/// Redis has 16 databases, the first is left to the usual node.
+pub const MAX_PROCESS_NODES: usize = 15;
+
+pub struct DevnetOptions {
+    pub nodes: usize,
+    /// How many of the nodes, from the first, keep mining.
+    pub miners: usize,
+    /// Node `n` listens on the three ports from `base_port + 3 * n`.
+    pub base_port: u16,
+    /// Run each node as a child process on its own Redis database, instead
+    /// of in this process on memory stores.
+    pub processes: bool,
+    /// Where child processes get their config and log, one directory each.
+    pub dir: PathBuf,
+}
+
+/// One config per node built on `base`: ids `devnet-0`, `devnet-1`..,
+/// peer to peer, HTTP and WebSocket ports in that order, every other node
+/// as a peer and Redis database `n + 1`.
+pub fn node_configs(base: &NodeConfig, options: &DevnetOptions) -> Result<Vec<NodeConfig>> {
+    if options.nodes == 0 {
+        bail!("a devnet needs at least one node");
+    }
+    if options.miners > options.nodes {
+        bail!("{} miners but only {} nodes", options.miners, options.nodes);
+    }
+    if options.processes && options.nodes > MAX_PROCESS_NODES {
+        bail!("at most {} nodes run as processes", MAX_PROCESS_NODES);
+    }
+    if usize::from(options.base_port) + 3 * options.nodes > usize::from(u16::MAX) + 1 {
+        bail!("not enough ports above {}", options.base_port);
+    }
+
+    let addr = |node: usize, offset: usize| {
+        let port = usize::from(options.base_port) + 3 * node + offset;
+        SocketAddr::from(([127, 0, 0, 1], port as u16))
+    };
+
+    (0..options.nodes)
+        .map(|node| {
+            let config = NodeConfig {
+                node_id: format!("devnet-{}", node),
+                db: database(&base.db, node + 1),
+                p2p_addr: addr(node, 0),
+                peers: (0..options.nodes)
+                    .filter(|peer| *peer != node)
+                    .map(|peer| addr(peer, 0))
+                    .collect(),
+                http_addr: addr(node, 1),
+                ws_addr: addr(node, 2),
+                mine: node < options.miners,
+                ..base.clone()
+            };
+            config.validate()?;
+            Ok(config)
+        })
+        .collect()
+}
+
+/// `db` pointed at Redis database `index`.
+pub fn database(db: &str, index: usize) -> String {
+    let server = match db.rsplit_once('/') {
+        Some((server, number))
+            if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            server
+        }
+        _ => db.trim_end_matches('/'),
+    };
+
+    format!("{}/{}", server, index)
+}
+
+/// Starts the devnet and runs it until Ctrl-C, or until a node stops.
+pub async fn run(base: &NodeConfig, options: DevnetOptions) -> Result<()> {
+    let configs = node_configs(base, &options)?;
+
+    match options.processes {
+        true => run_processes(configs, &options.dir).await,
+        false => run_in_process(configs).await,
+    }
+}
+
+async fn run_in_process(configs: Vec<NodeConfig>) -> Result<()> {
+    let mut rockets = vec![];
+
+    for config in configs {
+        let storage = Client::memory(config.node_id.clone(), MemoryStore::default());
+        print_node(&config);
+        rockets.push(server::build(config, storage, None).await?);
+    }
+
+    try_join_all(rockets.into_iter().map(|rocket| rocket.launch())).await?;
+    Ok(())
+}
+
+async fn run_processes(configs: Vec<NodeConfig>, dir: &Path) -> Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut children = vec![];
+
+    for config in &configs {
+        children.push(spawn_node(&exe, config, &dir.join(&config.node_id))?);
+        print_node(config);
+    }
+
+    let exited = select_all(children.iter_mut().map(|child| Box::pin(child.wait())));
+    let stopped = tokio::select! {
+        _ = tokio::signal::ctrl_c() => Ok(()),
+        (status, node, _) = exited => Err(anyhow!(
+            "node {} exited with {}, see its node.log",
+            configs[node].node_id,
+            status?
+        )),
+    };
+
+    println!("DEVNET STOPPING");
+    for child in &mut children {
+        let _ = child.kill().await;
+    }
+
+    stopped
+}
+
+/// Runs `node run` in `dir`, with the config written next to its log.
+/// Everything per node is passed as flags too, so a `.env` found from
+/// `dir` cannot override it.
+fn spawn_node(exe: &Path, config: &NodeConfig, dir: &Path) -> Result<Child> {
+    fs::create_dir_all(dir)?;
+    let dir = dir.canonicalize()?;
+    let config_path = dir.join(DEFAULT_CONFIG_FILE);
+    fs::write(&config_path, toml::to_string(config)?)?;
+    let log = File::create(dir.join("node.log"))?;
+
+    let peers: Vec<String> = config.peers.iter().map(SocketAddr::to_string).collect();
+    let mut command = Command::new(exe);
+    command
+        .arg("--config")
+        .arg(&config_path)
+        .args(["--node-id", &config.node_id, "--db", &config.db])
+        .args(["node", "run"])
+        .args(["--p2p-addr", &config.p2p_addr.to_string()])
+        .args(["--peers", &peers.join(",")])
+        .args(["--http-addr", &config.http_addr.to_string()])
+        .args(["--ws-addr", &config.ws_addr.to_string()]);
+    if config.mine {
+        command.arg("--mine");
+    }
+
+    Ok(command
+        .current_dir(&dir)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .kill_on_drop(true)
+        .spawn()?)
+}
+
+fn print_node(config: &NodeConfig) {
+    println!(
+        "DEVNET NODE {}: API http://{} WEBSOCKET ws://{} P2P {}{}",
+        config.node_id,
+        config.http_addr,
+        config.ws_addr,
+        config.p2p_addr,
+        if config.mine { " MINING" } else { "" }
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use crate::devnet::*;
+
+    fn options(nodes: usize, miners: usize) -> DevnetOptions {
+        DevnetOptions {
+            nodes,
+            miners,
+            base_port: DEVNET_BASE_PORT,
+            processes: false,
+            dir: PathBuf::from(DEVNET_DIR),
+        }
+    }
+
+    #[test]
+    fn node_configs_test() {
+        let configs = node_configs(&NodeConfig::default(), &options(3, 1)).unwrap();
+
+        assert_eq!(configs.len(), 3);
+        assert_eq!(configs[1].node_id, "devnet-1");
+        assert_eq!(configs[1].p2p_addr.port(), 9103);
+        assert_eq!(configs[1].http_addr.port(), 9104);
+        assert_eq!(configs[1].ws_addr.port(), 9105);
+        assert_eq!(
+            configs[1].peers,
+            vec![configs[0].p2p_addr, configs[2].p2p_addr]
+        );
+        assert_eq!(configs[2].db, "redis://127.0.0.1:6379/3");
+        assert!(configs[0].mine && !configs[1].mine);
+
+        assert!(node_configs(&NodeConfig::default(), &options(0, 0)).is_err());
+        assert!(node_configs(&NodeConfig::default(), &options(2, 3)).is_err());
+    }
+
+    #[test]
+    fn database_test() {
+        assert_eq!(
+            database("redis://127.0.0.1:6379", 2),
+            "redis://127.0.0.1:6379/2"
+        );
+        assert_eq!(
+            database("redis://127.0.0.1:6379/", 2),
+            "redis://127.0.0.1:6379/2"
+        );
+        assert_eq!(
+            database("redis://127.0.0.1:6379/0", 2),
+            "redis://127.0.0.1:6379/2"
+        );
+        assert_eq!(database("redis://redis/12", 1), "redis://redis/1");
+    }
+}
@@ -1,4 +1,8 @@
 pub mod blockchain;
+pub mod cli;
+pub mod config;
+pub mod devnet;
 pub mod network;
 pub mod server;
+pub mod simulation;
 pub mod storage;
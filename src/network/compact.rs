@@ -0,0 +1,186 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::mempool::Mempool;
+use crate::blockchain::transaction::{encode_transactions, Transaction, TxHash};
+
+pub type ShortId = [u8; 6];
+
+/// Transaction id salted with the block hash, so a collision in one block
+/// says nothing about the next one.
+pub fn short_id(block_hash: &BlockHash, tx_hash: &TxHash) -> ShortId {
+    let hash = Block::block_hash(&[&block_hash[..], &tx_hash[..]].concat());
+    hash[..6].try_into().unwrap()
+}
+
+/// A block announced as its header plus short ids of its transactions,
+/// which the receiver is expected to mostly have in its mempool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub short_ids: Vec<ShortId>,
+}
+
+impl CompactBlock {
+    /// `None` when the block data is not made of transactions.
+    pub fn new(block: &Block) -> Option<Self> {
+        let short_ids = block
+            .transactions()?
+            .iter()
+            .map(|transaction| short_id(&block.get_hash(), &transaction.hash()))
+            .collect();
+
+        Some(CompactBlock {
+            header: block.header(),
+            short_ids,
+        })
+    }
+}
+
+/// A compact block being rebuilt, one slot per transaction.
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    slots: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Fills every slot whose short id matches exactly one mempool transaction.
+    pub fn from_mempool(compact: &CompactBlock, mempool: &Mempool) -> Self {
+        let mut candidates: HashMap<ShortId, Option<Transaction>> = HashMap::new();
+
+        for transaction in mempool.transactions() {
+            let id = short_id(&compact.header.hash, &transaction.hash());
+            candidates
+                .entry(id)
+                .and_modify(|found| *found = None)
+                .or_insert(Some(transaction));
+        }
+
+        let slots = compact
+            .short_ids
+            .iter()
+            .map(|id| candidates.get(id).cloned().flatten())
+            .collect();
+
+        PartialBlock {
+            header: compact.header,
+            slots,
+        }
+    }
+
+    pub fn hash(&self) -> BlockHash {
+        self.header.hash
+    }
+
+    pub fn missing(&self) -> Vec<usize> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Puts the transactions a peer sent for the `missing` slots in place.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return false;
+        }
+
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            self.slots[index] = Some(transaction);
+        }
+        true
+    }
+
+    /// The rebuilt block, if every slot is filled and it hashes to the header.
+    pub fn block(&self) -> Option<Block> {
+        let transactions: Vec<Transaction> = self.slots.iter().cloned().collect::<Option<_>>()?;
+        let block = Block {
+            timestamp: self.header.timestamp,
+            difficulty: self.header.difficulty,
+            block_number: self.header.block_number,
+            nonce: self.header.nonce,
+            data: encode_transactions(&transactions),
+            hash: self.header.hash,
+            prev_hash: self.header.prev_hash,
+        };
+
+        block.has_valid_hash().then_some(block)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::blockchain::transaction::encode_transactions;
+    use crate::network::compact::*;
+
+    pub fn block_with(transactions: &[Transaction]) -> Block {
+        let data = encode_transactions(transactions);
+        let prev_hash = [1; 32];
+
+        Block {
+            timestamp: 10,
+            block_number: 2,
+            hash: Block::calculate_hash(10, &data, &prev_hash, 0, 0),
+            data,
+            prev_hash,
+            ..Block::default()
+        }
+    }
+
+    #[test]
+    fn rebuild_from_mempool_test() {
+        let transactions: Vec<Transaction> =
+            (0..3).map(|i| Transaction::new(i, vec![i as u8])).collect();
+        let block = block_with(&transactions);
+        let compact = CompactBlock::new(&block).unwrap();
+
+        let mut mempool = Mempool::new();
+        for transaction in &transactions {
+            mempool.insert(transaction.clone());
+        }
+        mempool.insert(Transaction::new(99, b"unrelated".to_vec()));
+
+        let partial = PartialBlock::from_mempool(&compact, &mempool);
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.block(), Some(block));
+    }
+
+    #[test]
+    fn fill_missing_transactions_test() {
+        let transactions: Vec<Transaction> =
+            (0..4).map(|i| Transaction::new(i, vec![i as u8])).collect();
+        let block = block_with(&transactions);
+        let compact = CompactBlock::new(&block).unwrap();
+
+        let mut mempool = Mempool::new();
+        mempool.insert(transactions[1].clone());
+        mempool.insert(transactions[2].clone());
+
+        let mut partial = PartialBlock::from_mempool(&compact, &mempool);
+        assert_eq!(partial.missing(), vec![0, 3]);
+        assert_eq!(partial.block(), None);
+
+        assert!(!partial.fill(vec![transactions[0].clone()]));
+        assert!(partial.fill(vec![transactions[0].clone(), transactions[3].clone()]));
+        assert_eq!(partial.block(), Some(block));
+    }
+
+    #[test]
+    fn wrong_transactions_fail_test() {
+        let transactions = vec![Transaction::new(1, b"real".to_vec())];
+        let block = block_with(&transactions);
+        let compact = CompactBlock::new(&block).unwrap();
+
+        let mut partial = PartialBlock::from_mempool(&compact, &Mempool::new());
+        partial.fill(vec![Transaction::new(1, b"fake".to_vec())]);
+
+        assert_eq!(partial.block(), None);
+        assert_eq!(CompactBlock::new(&Block::default()), None);
+    }
+}
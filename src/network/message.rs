@@ -0,0 +1,65 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::transaction::{Transaction, TxHash};
+use crate::network::compact::CompactBlock;
+
+/// A peer address as shared during discovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NetAddress {
+    pub address: SocketAddr,
+    pub last_seen: u64,
+}
+
+/// Something a node can announce and others can ask for by hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "kind", content = "hash")]
+pub enum InvItem {
+    Block(BlockHash),
+    Tx(TxHash),
+}
+
+/// Everything nodes say to each other, sent as one JSON document per line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", content = "payload")]
+pub enum Message {
+    /// First message on every connection, in both directions.
+    Version {
+        node_id: String,
+        listen_addr: SocketAddr,
+        /// Whether the node keeps every block body, true for nodes that
+        /// predate pruning and do not say.
+        #[serde(default = "archive_default")]
+        archive: bool,
+    },
+    Ping(u64),
+    Pong(u64),
+    /// Asks for the addresses a peer knows about.
+    GetAddr,
+    Addr(Vec<NetAddress>),
+    /// Announces items by hash, peers answer with `GetData` for the ones
+    /// they are missing.
+    Inv(Vec<InvItem>),
+    GetData(Vec<InvItem>),
+    Block(Block),
+    Tx(Transaction),
+    /// Asks for a block as a `CompactBlock`, answered with a full `Block`
+    /// when it doesn't carry transactions.
+    GetCompactBlock(BlockHash),
+    CompactBlock(CompactBlock),
+    /// Asks for the transactions at `indexes` of a compact block.
+    GetBlockTxn {
+        hash: BlockHash,
+        indexes: Vec<usize>,
+    },
+    BlockTxn {
+        hash: BlockHash,
+        transactions: Vec<Transaction>,
+    },
+}
+
+fn archive_default() -> bool {
+    true
+}
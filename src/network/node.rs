@@ -0,0 +1,798 @@
+use anyhow::{anyhow, Result};
+use rocket::serde::json::serde_json::{from_slice, to_string};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout};
+
+use crate::config::NodeConfig;
+use crate::network::message::{Message, NetAddress};
+use crate::network::peer::{Misbehavior, PeerInfo, PeerManager};
+use crate::storage::Client;
+
+pub const MAX_OUTBOUND: usize = 8;
+pub const MAX_MESSAGES_PER_SECOND: u32 = 200;
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_millis(500);
+pub const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_P2P_PORT: u16 = 7000;
+/// Most addresses sent in, or accepted from, a single `Addr` message.
+pub const MAX_ADDR_RESPONSE: usize = 100;
+/// Longest line, so message, read from a peer.
+pub const MAX_LINE_LEN: usize = 32 * 1024 * 1024;
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+pub struct NodeOptions {
+    pub node_id: String,
+    pub listen_addr: SocketAddr,
+    pub bootstrap: Vec<SocketAddr>,
+    /// Where to load and save the address book, if anywhere.
+    pub storage: Option<Client>,
+    /// Whether this node keeps every block body, told to peers.
+    pub archive: bool,
+}
+
+impl NodeOptions {
+    /// A node only tells peers it is an archive when it is not set to prune
+    /// and no block body was pruned from `storage` before.
+    pub async fn from_config(config: &NodeConfig, storage: Option<Client>) -> Self {
+        let pruned = match storage.clone() {
+            Some(mut db) => db.get_pruned_height().await > 0,
+            None => false,
+        };
+
+        NodeOptions {
+            node_id: config.node_id.clone(),
+            listen_addr: config.p2p_addr,
+            bootstrap: config.peers.clone(),
+            storage,
+            archive: config.prune.is_none() && !pruned,
+        }
+    }
+}
+
+type Reader = BufReader<OwnedReadHalf>;
+
+/// What reading a line from a peer came to.
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    Read(Vec<u8>),
+    TooLong,
+    Closed,
+}
+
+struct Connection {
+    node_id: String,
+    sender: UnboundedSender<Message>,
+    /// Tells the task reading from the peer to hang up.
+    closed: Arc<Notify>,
+}
+
+struct Shared {
+    node_id: String,
+    listen_addr: SocketAddr,
+    /// Where the address book is kept, if it is.
+    storage: Option<Client>,
+    archive: bool,
+    peers: Mutex<PeerManager>,
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+    inbox: UnboundedSender<(SocketAddr, Message)>,
+}
+
+/// Handle to the peer to peer side of this node. Received messages, other
+/// than the handshake and pings, come out of the receiver returned by
+/// `Node::start` together with the address of the peer that sent them.
+#[derive(Clone)]
+pub struct Node {
+    shared: Arc<Shared>,
+}
+
+impl Node {
+    pub async fn start(
+        options: NodeOptions,
+    ) -> Result<(Node, UnboundedReceiver<(SocketAddr, Message)>)> {
+        let listener = TcpListener::bind(options.listen_addr).await?;
+        let listen_addr = listener.local_addr()?;
+
+        let mut storage = options.storage;
+        let book = match &mut storage {
+            Some(db) => db.get_peers().await.unwrap_or_default(),
+            None => vec![],
+        };
+        let mut peers = PeerManager::from_address_book(book);
+        for address in options.bootstrap {
+            peers.add_address(address);
+        }
+
+        let (inbox, receiver) = unbounded_channel();
+        let node = Node {
+            shared: Arc::new(Shared {
+                node_id: options.node_id,
+                listen_addr,
+                storage,
+                archive: options.archive,
+                peers: Mutex::new(peers),
+                connections: Mutex::new(HashMap::new()),
+                inbox,
+            }),
+        };
+
+        let accepting = node.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, address)) = listener.accept().await {
+                let node = accepting.clone();
+                tokio::spawn(async move { node.run_connection(stream, address, true).await });
+            }
+        });
+
+        let maintaining = node.clone();
+        tokio::spawn(async move {
+            loop {
+                maintaining.maintain().await;
+                sleep(MAINTENANCE_INTERVAL).await;
+            }
+        });
+
+        Ok((node, receiver))
+    }
+
+    pub fn node_id(&self) -> String {
+        self.shared.node_id.clone()
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.shared.listen_addr
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.shared.peers.lock().unwrap().peers()
+    }
+
+    pub fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.shared
+            .connections
+            .lock()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    pub fn is_banned(&self, address: &SocketAddr) -> bool {
+        self.shared
+            .peers
+            .lock()
+            .unwrap()
+            .is_banned(address, unix_now())
+    }
+
+    pub fn add_peer(&self, address: SocketAddr) -> bool {
+        self.shared.peers.lock().unwrap().add_address(address)
+    }
+
+    pub fn send(&self, address: &SocketAddr, message: Message) -> Result<()> {
+        let connections = self.shared.connections.lock().unwrap();
+        let connection = connections
+            .get(address)
+            .ok_or_else(|| anyhow!("not connected to {}", address))?;
+
+        connection.sender.send(message)?;
+        Ok(())
+    }
+
+    pub fn broadcast(&self, message: Message) -> usize {
+        let connections = self.shared.connections.lock().unwrap();
+
+        connections
+            .values()
+            .filter(|connection| connection.sender.send(message.clone()).is_ok())
+            .count()
+    }
+
+    pub fn broadcast_except(&self, except: &SocketAddr, message: Message) -> usize {
+        let connections = self.shared.connections.lock().unwrap();
+
+        connections
+            .iter()
+            .filter(|(address, _)| *address != except)
+            .filter(|(_, connection)| connection.sender.send(message.clone()).is_ok())
+            .count()
+    }
+
+    /// Scores the peer and drops it when that gets it banned.
+    pub fn misbehaving(&self, address: &SocketAddr, kind: Misbehavior) -> bool {
+        let banned = self
+            .shared
+            .peers
+            .lock()
+            .unwrap()
+            .misbehaving(address, kind, unix_now());
+
+        if banned {
+            println!("BANNED PEER {}: {:?}", address, kind);
+            self.disconnect(address);
+        }
+        banned
+    }
+
+    pub fn disconnect(&self, address: &SocketAddr) {
+        // dropping the sender ends the writer, the notify ends the reader
+        if let Some(connection) = self.shared.connections.lock().unwrap().remove(address) {
+            connection.closed.notify_one();
+        }
+    }
+
+    async fn maintain(&self) {
+        let now = unix_now();
+        let to_connect = self
+            .shared
+            .peers
+            .lock()
+            .unwrap()
+            .to_connect(now, MAX_OUTBOUND);
+
+        for address in to_connect {
+            if address == self.shared.listen_addr {
+                continue;
+            }
+
+            self.shared.peers.lock().unwrap().connecting(address);
+            let node = self.clone();
+            tokio::spawn(async move {
+                match timeout(DIAL_TIMEOUT, TcpStream::connect(address)).await {
+                    Ok(Ok(stream)) => node.run_connection(stream, address, false).await,
+                    _ => node
+                        .shared
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .disconnected(&address, unix_now()),
+                }
+            });
+        }
+
+        self.save_peers().await;
+    }
+
+    async fn save_peers(&self) {
+        let mut db = match &self.shared.storage {
+            Some(db) => db.clone(),
+            None => return,
+        };
+        let book = self.shared.peers.lock().unwrap().address_book();
+
+        if let Err(err) = db.save_peers(&book).await {
+            println!("COULD NOT SAVE PEERS: {}", err);
+        }
+    }
+
+    async fn write(writer: &mut (impl AsyncWrite + Unpin), message: &Message) -> Result<()> {
+        let mut line = to_string(message)?;
+        line.push('\n');
+        writer.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Reads up to a newline, giving up on lines longer than `limit`.
+    async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), limit: usize) -> Line {
+        let mut line = vec![];
+        match (&mut *reader)
+            .take(limit as u64 + 1)
+            .read_until(b'\n', &mut line)
+            .await
+        {
+            Ok(0) | Err(_) => return Line::Closed,
+            Ok(_) => {}
+        }
+
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        } else if line.len() > limit {
+            return Line::TooLong;
+        }
+        Line::Read(line)
+    }
+
+    /// Handshakes and then pumps messages until either side hangs up.
+    /// `address` is the one we dialed, or the one an inbound peer dialed
+    /// from.
+    async fn run_connection(&self, stream: TcpStream, address: SocketAddr, inbound: bool) {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        if let Some(closed) = self.handshake(writer, &mut reader, address, inbound).await {
+            self.discover(address, inbound);
+            self.pump(reader, address, closed.clone()).await;
+
+            // a connection replaced by a newer one leaves the peer connected
+            let replaced = {
+                let mut connections = self.shared.connections.lock().unwrap();
+                match connections.get(&address) {
+                    Some(current) if !Arc::ptr_eq(&current.closed, &closed) => true,
+                    Some(_) => {
+                        connections.remove(&address);
+                        false
+                    }
+                    None => false,
+                }
+            };
+            if !replaced {
+                self.shared
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .disconnected(&address, unix_now());
+            }
+        } else if !inbound {
+            // a duplicate dial must not mark the live connection as lost
+            let connections = self.shared.connections.lock().unwrap();
+            if !connections.contains_key(&address) {
+                self.shared
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .disconnected(&address, unix_now());
+            }
+        }
+    }
+
+    /// Asks a new peer for the addresses it knows and, when we dialed it,
+    /// tells our other peers the address answered. Where an inbound peer
+    /// says it listens is unchecked and not passed on.
+    fn discover(&self, address: SocketAddr, inbound: bool) {
+        let _ = self.send(&address, Message::GetAddr);
+
+        if !inbound {
+            let reached = NetAddress {
+                address,
+                last_seen: unix_now(),
+            };
+            self.broadcast_except(&address, Message::Addr(vec![reached]));
+        }
+    }
+
+    fn learn(&self, from: &SocketAddr, addresses: Vec<NetAddress>) -> bool {
+        if addresses.len() > MAX_ADDR_RESPONSE {
+            return self.misbehaving(from, Misbehavior::Spam);
+        }
+
+        let now = unix_now();
+        let mut peers = self.shared.peers.lock().unwrap();
+        for heard in addresses {
+            if heard.address != self.shared.listen_addr {
+                peers.learn(&heard, now);
+            }
+        }
+        false
+    }
+
+    async fn handshake(
+        &self,
+        mut writer: impl AsyncWrite + Unpin + Send + 'static,
+        reader: &mut Reader,
+        address: SocketAddr,
+        inbound: bool,
+    ) -> Option<Arc<Notify>> {
+        let version = Message::Version {
+            node_id: self.shared.node_id.clone(),
+            listen_addr: self.shared.listen_addr,
+            archive: self.shared.archive,
+        };
+        Node::write(&mut writer, &version).await.ok()?;
+
+        let Line::Read(line) = Node::read_line(reader, MAX_LINE_LEN).await else {
+            return None;
+        };
+        let (node_id, listen_addr, archive) = match from_slice(&line) {
+            Ok(Message::Version {
+                node_id,
+                listen_addr,
+                archive,
+            }) => (node_id, listen_addr, archive),
+            _ => return None,
+        };
+
+        // when two nodes dial each other at once, both keep the connection
+        // dialed by the lower node id
+        let preferred = inbound != (self.shared.node_id < node_id);
+
+        let (sender, mut receiver) = unbounded_channel::<Message>();
+        let closed = Arc::new(Notify::new());
+        {
+            let mut connections = self.shared.connections.lock().unwrap();
+            let duplicate = connections
+                .iter()
+                .find(|(_, connection)| connection.node_id == node_id)
+                .map(|(address, _)| *address);
+            let allowed = (duplicate.is_none() || preferred) && node_id != self.shared.node_id && {
+                let mut peers = self.shared.peers.lock().unwrap();
+                match inbound {
+                    true => {
+                        peers.accepted(address, listen_addr, node_id.clone(), archive, unix_now())
+                    }
+                    false => peers.connected_to(address, node_id.clone(), archive, unix_now()),
+                }
+            };
+
+            if !allowed {
+                return None;
+            }
+
+            if let Some(replaced) = duplicate.and_then(|address| connections.remove(&address)) {
+                replaced.closed.notify_one();
+            }
+            let connection = Connection {
+                node_id,
+                sender,
+                closed: closed.clone(),
+            };
+            connections.insert(address, connection);
+        }
+
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if Node::write(&mut writer, &message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Some(closed)
+    }
+
+    async fn pump(&self, mut reader: Reader, address: SocketAddr, closed: Arc<Notify>) {
+        let mut window = (unix_now(), 0u32);
+
+        loop {
+            let line = tokio::select! {
+                line = Node::read_line(&mut reader, MAX_LINE_LEN) => match line {
+                    Line::Read(line) => line,
+                    Line::TooLong => {
+                        self.misbehaving(&address, Misbehavior::MalformedMessage);
+                        break;
+                    }
+                    Line::Closed => break,
+                },
+                _ = closed.notified() => break,
+            };
+            let now = unix_now();
+
+            window = if window.0 == now {
+                (now, window.1 + 1)
+            } else {
+                (now, 1)
+            };
+            if window.1 == MAX_MESSAGES_PER_SECOND + 1
+                && self.misbehaving(&address, Misbehavior::Spam)
+            {
+                break;
+            }
+
+            let message: Message = match from_slice(&line) {
+                Ok(message) => message,
+                Err(_) => {
+                    if self.misbehaving(&address, Misbehavior::MalformedMessage) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            self.shared.peers.lock().unwrap().seen(&address, now);
+            match message {
+                Message::Ping(nonce) => {
+                    let _ = self.send(&address, Message::Pong(nonce));
+                }
+                Message::Pong(_) => {}
+                Message::GetAddr => {
+                    let mut known = self
+                        .shared
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .known_addresses(MAX_ADDR_RESPONSE + 1, now);
+                    known.retain(|known| known.address != address);
+                    known.truncate(MAX_ADDR_RESPONSE);
+                    let _ = self.send(&address, Message::Addr(known));
+                }
+                Message::Addr(addresses) => {
+                    if self.learn(&address, addresses) {
+                        break;
+                    }
+                }
+                Message::Version { .. } => {
+                    if self.misbehaving(&address, Misbehavior::MalformedMessage) {
+                        break;
+                    }
+                }
+                message => {
+                    let _ = self.shared.inbox.send((address, message));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::blockchain::block::Block;
+    use crate::network::message::{Message, NetAddress};
+    use crate::network::node::*;
+    use crate::network::peer::PeerState;
+    use crate::storage::MemoryStore;
+    use std::time::Instant;
+
+    pub async fn start_node(
+        node_id: &str,
+        bootstrap: Vec<SocketAddr>,
+    ) -> (Node, UnboundedReceiver<(SocketAddr, Message)>) {
+        Node::start(NodeOptions {
+            node_id: node_id.to_string(),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            bootstrap,
+            storage: None,
+            archive: true,
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn wait_for(mut condition: impl FnMut() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if condition() {
+                return true;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn archive_handshake_test() {
+        let (archive, _) = start_node("archive", vec![]).await;
+        let (pruned, _) = Node::start(NodeOptions {
+            node_id: "pruned".to_string(),
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            bootstrap: vec![archive.listen_addr()],
+            storage: None,
+            archive: false,
+        })
+        .await
+        .unwrap();
+
+        assert!(
+            wait_for(|| archive
+                .peers()
+                .iter()
+                .any(|peer| peer.state == PeerState::Connected))
+            .await
+        );
+        let inbound = archive
+            .peers()
+            .into_iter()
+            .find(|peer| peer.inbound)
+            .unwrap();
+        assert!(!inbound.archive);
+        assert!(wait_for(|| pruned.connected_peers().len() == 1).await);
+        assert!(pruned.peers()[0].archive);
+    }
+
+    #[tokio::test]
+    async fn pruned_storage_is_not_archive_test() {
+        let config = NodeConfig::default();
+        let mut storage = Client::memory("0".to_string(), MemoryStore::default());
+        assert!(
+            NodeOptions::from_config(&config, Some(storage.clone()))
+                .await
+                .archive
+        );
+
+        storage.set_pruned_height(5).await.unwrap();
+        assert!(
+            !NodeOptions::from_config(&config, Some(storage))
+                .await
+                .archive
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_and_send_test() {
+        let (first, mut first_inbox) = start_node("first", vec![]).await;
+        let (second, _) = start_node("second", vec![first.listen_addr()]).await;
+
+        assert!(
+            wait_for(|| first.connected_peers().len() == 1
+                && second.connected_peers() == vec![first.listen_addr()])
+            .await
+        );
+
+        second.broadcast(Message::Block(Block::default()));
+        let (from, message) = timeout(Duration::from_secs(10), first_inbox.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(from, first.connected_peers()[0]);
+        assert_eq!(message, Message::Block(Block::default()));
+
+        let inbound = first.peers().into_iter().find(|peer| peer.inbound).unwrap();
+        assert_eq!(inbound.address, from);
+        assert_eq!(inbound.node_id, Some("second".to_string()));
+        assert_eq!(inbound.listen_addr, Some(second.listen_addr()));
+        assert!(first
+            .peers()
+            .iter()
+            .any(|peer| peer.address == second.listen_addr() && !peer.inbound));
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_ban_peer_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let fake_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let mut stream = TcpStream::connect(node.listen_addr()).await.unwrap();
+        let version = Message::Version {
+            node_id: "fake".to_string(),
+            listen_addr: fake_addr,
+            archive: true,
+        };
+
+        let dialed_from = stream.local_addr().unwrap();
+
+        Node::write(&mut stream, &version).await.unwrap();
+        for _ in 0..5 {
+            let _ = stream.write_all(b"not a message\n").await;
+        }
+
+        assert!(wait_for(|| node.is_banned(&dialed_from)).await);
+        assert!(wait_for(|| node.connected_peers().is_empty()).await);
+
+        // the same host coming back from another port is still refused
+        let mut stream = TcpStream::connect(node.listen_addr()).await.unwrap();
+        assert_ne!(stream.local_addr().unwrap(), dialed_from);
+        Node::write(&mut stream, &version).await.unwrap();
+        let mut received = vec![];
+        let _ = timeout(Duration::from_secs(5), stream.read_to_end(&mut received)).await;
+
+        assert!(node.connected_peers().is_empty());
+        assert!(!node
+            .peers()
+            .iter()
+            .any(|peer| peer.state == PeerState::Connected));
+    }
+
+    #[tokio::test]
+    async fn discovers_peers_test() {
+        let (bootstrap, _) = start_node("bootstrap", vec![]).await;
+        let mut nodes: Vec<Node> = vec![];
+        for id in ["a", "b", "c"] {
+            nodes.push(start_node(id, vec![bootstrap.listen_addr()]).await.0);
+        }
+
+        for node in &nodes {
+            assert!(wait_for(|| node.connected_peers().len() == nodes.len()).await);
+
+            let known: Vec<SocketAddr> = node.peers().iter().map(|peer| peer.address).collect();
+            assert!(known.contains(&bootstrap.listen_addr()));
+            assert!(!known.contains(&node.listen_addr()));
+            for other in &nodes {
+                if other.listen_addr() != node.listen_addr() {
+                    assert!(known.contains(&other.listen_addr()));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_only_dialed_addresses_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let (watcher, _) = start_node("watcher", vec![node.listen_addr()]).await;
+        assert!(wait_for(|| node.connected_peers().len() == 1).await);
+
+        let fake_addr = SocketAddr::from(([127, 0, 0, 1], 3));
+        let mut stream = TcpStream::connect(node.listen_addr()).await.unwrap();
+        let version = Message::Version {
+            node_id: "fake".to_string(),
+            listen_addr: fake_addr,
+            archive: true,
+        };
+        Node::write(&mut stream, &version).await.unwrap();
+        assert!(wait_for(|| node.connected_peers().len() == 2).await);
+
+        let (dialed, _) = start_node("dialed", vec![]).await;
+        node.add_peer(dialed.listen_addr());
+
+        assert!(
+            wait_for(|| watcher
+                .peers()
+                .iter()
+                .any(|peer| peer.address == dialed.listen_addr()))
+            .await
+        );
+        assert!(!watcher.peers().iter().any(|peer| peer.address == fake_addr));
+    }
+
+    #[tokio::test]
+    async fn oversized_addr_is_spam_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let fake_addr = SocketAddr::from(([127, 0, 0, 1], 2));
+        let mut stream = TcpStream::connect(node.listen_addr()).await.unwrap();
+        let version = Message::Version {
+            node_id: "fake".to_string(),
+            listen_addr: fake_addr,
+            archive: true,
+        };
+        let addresses: Vec<NetAddress> = (0..=MAX_ADDR_RESPONSE as u16)
+            .map(|port| NetAddress {
+                address: SocketAddr::from(([127, 0, 0, 1], 20000 + port)),
+                last_seen: 0,
+            })
+            .collect();
+
+        let dialed_from = stream.local_addr().unwrap();
+
+        Node::write(&mut stream, &version).await.unwrap();
+        Node::write(&mut stream, &Message::Addr(addresses))
+            .await
+            .unwrap();
+
+        assert!(
+            wait_for(|| node
+                .peers()
+                .iter()
+                .any(|peer| peer.address == dialed_from && peer.score > 0))
+            .await
+        );
+        let known: Vec<SocketAddr> = node.peers().iter().map(|peer| peer.address).collect();
+        assert_eq!(known.len(), 2);
+        assert!(known.contains(&fake_addr));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_disconnect_test() {
+        let (first, _) = start_node("first", vec![]).await;
+        let (second, _) = start_node("second", vec![first.listen_addr()]).await;
+
+        assert!(wait_for(|| second.connected_peers().len() == 1).await);
+        second.disconnect(&first.listen_addr());
+        assert!(wait_for(|| second.connected_peers().is_empty()).await);
+        // either side may dial again, the one that dialed before was second
+        assert!(wait_for(|| second.connected_peers().len() == 1).await);
+        assert!(second
+            .peers()
+            .iter()
+            .any(|peer| peer.node_id == Some("first".to_string())
+                && peer.state == PeerState::Connected
+                && peer.failures == 0));
+    }
+
+    #[tokio::test]
+    async fn read_line_test() {
+        let mut reader = BufReader::new(&b"{}\nabcdef\n"[..]);
+        assert_eq!(
+            Node::read_line(&mut reader, 4).await,
+            Line::Read(b"{}".to_vec())
+        );
+        assert_eq!(Node::read_line(&mut reader, 4).await, Line::TooLong);
+
+        let mut reader = BufReader::new(&b"abcd\n"[..]);
+        assert_eq!(
+            Node::read_line(&mut reader, 4).await,
+            Line::Read(b"abcd".to_vec())
+        );
+        assert_eq!(Node::read_line(&mut reader, 4).await, Line::Closed);
+    }
+}
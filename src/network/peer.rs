@@ -0,0 +1,555 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::network::message::NetAddress;
+
+/// Score at which a peer gets banned.
+pub const BAN_SCORE: u32 = 100;
+pub const BAN_TIME: u64 = 60 * 60 * 24; // 1 day
+pub const RECONNECT_BASE: u64 = 1; // 1 second
+pub const RECONNECT_MAX: u64 = 60 * 10; // 10 minutes
+/// Size of the address book, the stalest addresses are dropped past it.
+pub const MAX_ADDRESSES: usize = 1000;
+/// Number of banned hosts remembered, the bans closest to expiry go first.
+pub const MAX_BANS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub enum PeerState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Banned,
+}
+
+/// Things a peer can do wrong, each adding to its score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    InvalidBlock,
+    InvalidTransaction,
+    MalformedMessage,
+    Spam,
+}
+
+impl Misbehavior {
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 20,
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::Spam => 10,
+        }
+    }
+}
+
+/// The part of a peer that survives restarts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PeerAddress {
+    pub address: SocketAddr,
+    pub last_seen: u64,
+    pub banned_until: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PeerInfo {
+    pub address: SocketAddr,
+    pub node_id: Option<String>,
+    /// Whether the peer said it keeps every block body.
+    pub archive: bool,
+    pub state: PeerState,
+    pub score: u32,
+    pub failures: u32,
+    pub last_seen: u64,
+    pub next_attempt: u64,
+    pub banned_until: u64,
+    /// Whether the peer dialed us, its address then being the port it
+    /// dialed from, which is nothing to dial back or share.
+    pub inbound: bool,
+    /// Where an inbound peer says it accepts connections.
+    pub listen_addr: Option<SocketAddr>,
+}
+
+impl PeerInfo {
+    fn new(address: SocketAddr) -> Self {
+        PeerInfo {
+            address,
+            node_id: None,
+            archive: true,
+            state: PeerState::Disconnected,
+            score: 0,
+            failures: 0,
+            last_seen: 0,
+            next_attempt: 0,
+            banned_until: 0,
+            inbound: false,
+            listen_addr: None,
+        }
+    }
+}
+
+/// Known peers and what we know about them. Every method takes the current
+/// unix time in seconds so callers control the clock.
+#[derive(Debug, Default)]
+pub struct PeerManager {
+    peers: HashMap<SocketAddr, PeerInfo>,
+    /// Bans are per host, a banned peer cannot come back from another port.
+    bans: HashMap<IpAddr, u64>,
+}
+
+impl PeerManager {
+    pub fn new() -> Self {
+        PeerManager::default()
+    }
+
+    pub fn from_address_book(book: Vec<PeerAddress>) -> Self {
+        let mut manager = PeerManager::new();
+
+        for entry in book {
+            let mut peer = PeerInfo::new(entry.address);
+            peer.last_seen = entry.last_seen;
+            peer.banned_until = entry.banned_until;
+            if entry.banned_until > 0 {
+                manager.ban(entry.address.ip(), entry.banned_until, 0);
+            }
+            manager.peers.insert(entry.address, peer);
+        }
+
+        manager
+    }
+
+    pub fn address_book(&self) -> Vec<PeerAddress> {
+        let mut book: Vec<PeerAddress> = self
+            .peers
+            .values()
+            .filter(|peer| !peer.inbound)
+            .map(|peer| PeerAddress {
+                address: peer.address,
+                last_seen: peer.last_seen,
+                banned_until: peer.banned_until,
+            })
+            .collect();
+
+        book.sort_by_key(|entry| entry.address);
+        book
+    }
+
+    /// Remembers an address, returns whether it was unknown and got stored.
+    pub fn add_address(&mut self, address: SocketAddr) -> bool {
+        if self.peers.contains_key(&address) {
+            return false;
+        }
+
+        if self.peers.len() >= MAX_ADDRESSES && !self.evict_stalest() {
+            return false;
+        }
+
+        self.peers.insert(address, PeerInfo::new(address));
+        true
+    }
+
+    /// Drops the stalest peer we are not talking to, bans outlive it since
+    /// they are kept apart.
+    fn evict_stalest(&mut self) -> bool {
+        let stalest = self
+            .peers
+            .values()
+            .filter(|peer| matches!(peer.state, PeerState::Disconnected | PeerState::Banned))
+            .min_by_key(|peer| peer.last_seen)
+            .map(|peer| peer.address);
+
+        match stalest {
+            Some(address) => self.peers.remove(&address).is_some(),
+            None => false,
+        }
+    }
+
+    /// Records an address heard from a peer. Timestamps from the future are
+    /// capped to `now`. Returns whether the address was new.
+    pub fn learn(&mut self, heard: &NetAddress, now: u64) -> bool {
+        let is_new = self.add_address(heard.address);
+
+        if let Some(peer) = self.peers.get_mut(&heard.address) {
+            peer.last_seen = peer.last_seen.max(heard.last_seen.min(now));
+        }
+        is_new
+    }
+
+    /// Up to `limit` addresses worth sharing, most recently seen first.
+    pub fn known_addresses(&self, limit: usize, now: u64) -> Vec<NetAddress> {
+        let mut known: Vec<NetAddress> = self
+            .peers
+            .values()
+            .filter(|peer| !peer.inbound && !self.is_banned(&peer.address, now))
+            .map(|peer| NetAddress {
+                address: peer.address,
+                last_seen: peer.last_seen,
+            })
+            .collect();
+
+        known.sort_by_key(|known| std::cmp::Reverse(known.last_seen));
+        known.truncate(limit);
+        known
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&PeerInfo> {
+        self.peers.get(address)
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.address);
+        peers
+    }
+
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        self.peers
+            .values()
+            .filter(|peer| peer.state == PeerState::Connected)
+            .map(|peer| peer.address)
+            .collect()
+    }
+
+    pub fn connected_count(&self) -> usize {
+        self.connected().len()
+    }
+
+    /// Whether the host behind `address` is banned, whatever its port.
+    pub fn is_banned(&self, address: &SocketAddr, now: u64) -> bool {
+        self.bans
+            .get(&address.ip())
+            .is_some_and(|banned_until| *banned_until > now)
+    }
+
+    fn ban(&mut self, host: IpAddr, banned_until: u64, now: u64) {
+        self.bans.retain(|_, until| *until > now);
+
+        if !self.bans.contains_key(&host) && self.bans.len() >= MAX_BANS {
+            let soonest = self
+                .bans
+                .iter()
+                .min_by_key(|(_, until)| **until)
+                .map(|(host, _)| *host);
+            if let Some(soonest) = soonest {
+                self.bans.remove(&soonest);
+            }
+        }
+
+        let until = self.bans.entry(host).or_default();
+        *until = (*until).max(banned_until);
+    }
+
+    /// Addresses worth dialing now, leaving room for at most `max_outbound`
+    /// connections in total. Peers that dialed us are not dialed back.
+    pub fn to_connect(&self, now: u64, max_outbound: usize) -> Vec<SocketAddr> {
+        let busy = self
+            .peers
+            .values()
+            .filter(|peer| matches!(peer.state, PeerState::Connected | PeerState::Connecting))
+            .count();
+        let listening: Vec<SocketAddr> = self
+            .peers
+            .values()
+            .filter(|peer| peer.state == PeerState::Connected)
+            .filter_map(|peer| peer.listen_addr)
+            .collect();
+
+        let mut candidates: Vec<&PeerInfo> = self
+            .peers
+            .values()
+            .filter(|peer| !peer.inbound && !listening.contains(&peer.address))
+            .filter(|peer| peer.state == PeerState::Disconnected || peer.state == PeerState::Banned)
+            .filter(|peer| !self.is_banned(&peer.address, now) && peer.next_attempt <= now)
+            .collect();
+
+        candidates.sort_by_key(|peer| (peer.failures, std::cmp::Reverse(peer.last_seen)));
+        candidates
+            .into_iter()
+            .take(max_outbound.saturating_sub(busy))
+            .map(|peer| peer.address)
+            .collect()
+    }
+
+    pub fn connecting(&mut self, address: SocketAddr) {
+        self.add_address(address);
+        let peer = self.peers.get_mut(&address).unwrap();
+        peer.state = PeerState::Connecting;
+    }
+
+    /// Returns false when the peer is banned and must be dropped.
+    pub fn connected_to(
+        &mut self,
+        address: SocketAddr,
+        node_id: String,
+        archive: bool,
+        now: u64,
+    ) -> bool {
+        if self.is_banned(&address, now) {
+            return false;
+        }
+
+        self.add_address(address);
+        let Some(peer) = self.peers.get_mut(&address) else {
+            return false;
+        };
+        peer.state = PeerState::Connected;
+        peer.node_id = Some(node_id);
+        peer.archive = archive;
+        peer.failures = 0;
+        peer.last_seen = now;
+        true
+    }
+
+    /// A peer dialed us from `address`, saying it listens on `listen_addr`,
+    /// which is only learned like any heard address. Returns false when the
+    /// peer is banned and must be dropped.
+    pub fn accepted(
+        &mut self,
+        address: SocketAddr,
+        listen_addr: SocketAddr,
+        node_id: String,
+        archive: bool,
+        now: u64,
+    ) -> bool {
+        if !self.connected_to(address, node_id, archive, now) {
+            return false;
+        }
+
+        let peer = self.peers.get_mut(&address).unwrap();
+        peer.inbound = true;
+        peer.listen_addr = Some(listen_addr);
+        self.learn(
+            &NetAddress {
+                address: listen_addr,
+                last_seen: now,
+            },
+            now,
+        );
+        true
+    }
+
+    pub fn seen(&mut self, address: &SocketAddr, now: u64) {
+        if let Some(peer) = self.peers.get_mut(address) {
+            peer.last_seen = now;
+        }
+    }
+
+    /// A dial failed or a connection dropped, try again with exponential
+    /// backoff. Peers that dialed us are forgotten, their ban is kept apart.
+    pub fn disconnected(&mut self, address: &SocketAddr, now: u64) {
+        let peer = match self.peers.get_mut(address) {
+            Some(peer) => peer,
+            None => return,
+        };
+        if peer.inbound {
+            self.peers.remove(address);
+            return;
+        }
+
+        if peer.state != PeerState::Banned {
+            peer.state = PeerState::Disconnected;
+        }
+        peer.failures += 1;
+        peer.next_attempt = now
+            + RECONNECT_BASE
+                .saturating_mul(2u64.saturating_pow(peer.failures - 1))
+                .min(RECONNECT_MAX);
+    }
+
+    /// Adds the penalty to the peer's score, returns whether it got banned.
+    pub fn misbehaving(&mut self, address: &SocketAddr, kind: Misbehavior, now: u64) -> bool {
+        let peer = match self.peers.get_mut(address) {
+            Some(peer) => peer,
+            None => return false,
+        };
+
+        peer.score += kind.penalty();
+        if peer.score < BAN_SCORE {
+            return false;
+        }
+
+        peer.score = 0;
+        peer.state = PeerState::Banned;
+        peer.banned_until = now + BAN_TIME;
+        self.ban(address.ip(), now + BAN_TIME, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::network::peer::*;
+    use std::net::Ipv4Addr;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn misbehavior_bans_peer_test() {
+        let mut manager = PeerManager::new();
+        let peer = address(7001);
+        manager.connected_to(peer, "1".to_string(), true, 10);
+
+        for _ in 0..4 {
+            assert!(!manager.misbehaving(&peer, Misbehavior::MalformedMessage, 10));
+        }
+        assert!(!manager.misbehaving(&peer, Misbehavior::Spam, 10));
+        assert!(manager.misbehaving(&peer, Misbehavior::Spam, 10));
+        assert!(manager.is_banned(&peer, 11));
+        assert_eq!(manager.get(&peer).unwrap().state, PeerState::Banned);
+
+        assert!(!manager.connected_to(peer, "1".to_string(), true, 11));
+        assert!(manager.to_connect(11, 8).is_empty());
+        assert!(!manager.is_banned(&peer, 10 + BAN_TIME));
+        assert_eq!(manager.to_connect(10 + BAN_TIME, 8), vec![peer]);
+    }
+
+    #[test]
+    fn reconnect_backoff_test() {
+        let mut manager = PeerManager::new();
+        let peer = address(7002);
+        manager.add_address(peer);
+
+        manager.connecting(peer);
+        manager.disconnected(&peer, 100);
+        assert!(manager.to_connect(100, 8).is_empty());
+        assert_eq!(manager.to_connect(101, 8), vec![peer]);
+
+        manager.connecting(peer);
+        manager.disconnected(&peer, 101);
+        assert_eq!(manager.get(&peer).unwrap().next_attempt, 103);
+
+        for now in 0..20 {
+            manager.disconnected(&peer, now);
+        }
+        assert_eq!(manager.get(&peer).unwrap().next_attempt, 19 + RECONNECT_MAX);
+
+        manager.connected_to(peer, "2".to_string(), true, 200);
+        assert_eq!(manager.get(&peer).unwrap().failures, 0);
+    }
+
+    #[test]
+    fn to_connect_respects_slots_test() {
+        let mut manager = PeerManager::new();
+        for port in 7000..7005 {
+            manager.add_address(address(port));
+        }
+        manager.connected_to(address(7000), "0".to_string(), true, 1);
+
+        assert_eq!(manager.to_connect(1, 3).len(), 2);
+        assert_eq!(manager.to_connect(1, 1).len(), 0);
+        assert_eq!(manager.connected(), vec![address(7000)]);
+    }
+
+    #[test]
+    fn learn_addresses_test() {
+        let mut manager = PeerManager::new();
+        let heard = NetAddress {
+            address: address(7005),
+            last_seen: 40,
+        };
+
+        assert!(manager.learn(&heard, 50));
+        assert!(!manager.learn(&heard, 50));
+        assert_eq!(manager.get(&heard.address).unwrap().last_seen, 40);
+
+        let future = NetAddress {
+            last_seen: 1000,
+            ..heard
+        };
+        manager.learn(&future, 60);
+        assert_eq!(manager.get(&heard.address).unwrap().last_seen, 60);
+
+        manager.learn(&heard, 70);
+        assert_eq!(manager.get(&heard.address).unwrap().last_seen, 60);
+    }
+
+    #[test]
+    fn bounded_address_book_test() {
+        let mut manager = PeerManager::new();
+        for port in 0..MAX_ADDRESSES as u16 {
+            let heard = NetAddress {
+                address: address(10000 + port),
+                last_seen: 100 + port as u64,
+            };
+            manager.learn(&heard, 10000);
+        }
+        manager.connected_to(address(10000), "0".to_string(), true, 10000);
+
+        assert!(manager.add_address(address(9999)));
+        assert_eq!(manager.peers().len(), MAX_ADDRESSES);
+        assert!(manager.get(&address(10000)).is_some());
+        assert!(manager.get(&address(10001)).is_none());
+
+        let known = manager.known_addresses(3, 10000);
+        assert_eq!(known[0].address, address(10000));
+        assert_eq!(known.len(), 3);
+    }
+
+    #[test]
+    fn inbound_peer_test() {
+        let mut manager = PeerManager::new();
+        let (inbound, listening) = (address(50123), address(7006));
+
+        assert!(manager.accepted(inbound, listening, "6".to_string(), true, 10));
+        assert!(manager.get(&inbound).unwrap().inbound);
+        assert_eq!(manager.get(&listening).unwrap().last_seen, 10);
+        assert_eq!(manager.address_book().len(), 1);
+        assert_eq!(manager.known_addresses(10, 10).len(), 1);
+        assert!(manager.to_connect(10, 8).is_empty());
+
+        manager.disconnected(&inbound, 11);
+        assert!(manager.get(&inbound).is_none());
+        assert_eq!(manager.to_connect(11, 8), vec![listening]);
+
+        manager.accepted(inbound, listening, "6".to_string(), true, 12);
+        manager.misbehaving(&inbound, Misbehavior::InvalidBlock, 12);
+        manager.disconnected(&inbound, 12);
+        assert!(manager.get(&inbound).is_none());
+        assert!(manager.is_banned(&inbound, 13));
+        assert!(manager.is_banned(&listening, 13));
+        assert!(!manager.accepted(address(50124), listening, "6".to_string(), true, 13));
+        assert!(manager.to_connect(13, 8).is_empty());
+    }
+
+    #[test]
+    fn bounded_bans_test() {
+        let mut manager = PeerManager::new();
+        for host in 0..MAX_BANS as u32 + 1 {
+            let peer = SocketAddr::from((Ipv4Addr::from(0x0a00_0000 + host), 7000));
+            manager.accepted(peer, peer, host.to_string(), true, host as u64);
+            manager.misbehaving(&peer, Misbehavior::InvalidBlock, host as u64);
+            manager.disconnected(&peer, host as u64);
+        }
+
+        assert!(manager.peers().is_empty());
+        assert_eq!(manager.bans.len(), MAX_BANS);
+        assert!(!manager.is_banned(&SocketAddr::from(([10, 0, 0, 0], 7001)), 1));
+        assert!(manager.is_banned(&SocketAddr::from(([10, 0, 0, 1], 7001)), 1));
+
+        manager.ban(IpAddr::from([10, 0, 0, 1]), 3 * BAN_TIME, 2 * BAN_TIME);
+        assert_eq!(manager.bans.len(), 1);
+    }
+
+    #[test]
+    fn address_book_test() {
+        let mut manager = PeerManager::new();
+        manager.connected_to(address(7003), "3".to_string(), true, 50);
+        manager.add_address(address(7004));
+        manager.misbehaving(&address(7003), Misbehavior::InvalidBlock, 60);
+
+        let book = manager.address_book();
+        let restored = PeerManager::from_address_book(book.clone());
+
+        assert_eq!(book.len(), 2);
+        assert_eq!(restored.address_book(), book);
+        assert!(restored.is_banned(&address(7003), 61));
+        assert_eq!(
+            restored.get(&address(7004)).unwrap().state,
+            PeerState::Disconnected
+        );
+    }
+}
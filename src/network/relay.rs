@@ -0,0 +1,829 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::events::{ChainEvent, EventBus};
+use crate::blockchain::mempool::{Mempool, MAX_MEMPOOL_SIZE};
+use crate::blockchain::transaction::Transaction;
+use crate::network::compact::{CompactBlock, PartialBlock};
+use crate::network::message::{InvItem, Message};
+use crate::network::node::Node;
+use crate::network::peer::Misbehavior;
+use crate::network::transport::Transport;
+
+pub const SEEN_CACHE_SIZE: usize = 5000;
+pub const RECENT_BLOCKS: usize = 100;
+/// Most items accepted in a single `Inv` or `GetData`.
+pub const MAX_INV_ITEMS: usize = 500;
+/// Compact blocks waiting on transactions at once, the oldest dropped past it.
+pub const MAX_PARTIAL_BLOCKS: usize = 16;
+/// Most transactions a compact block or a transaction request may list, a
+/// block holds at most a full mempool.
+pub const MAX_BLOCK_TXS: usize = MAX_MEMPOOL_SIZE;
+/// Seconds an announcer has to deliver what we asked for before another
+/// announcer gets asked.
+pub const REQUEST_TIMEOUT: u64 = 20;
+/// Most items waited on at once, more are only asked for once some arrive
+/// or time out.
+pub const MAX_REQUESTED: usize = 5000;
+
+/// Bounded set that forgets the oldest entries first.
+#[derive(Debug)]
+pub struct SeenCache<T> {
+    items: HashSet<T>,
+    order: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T: Copy + Eq + Hash> SeenCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        SeenCache {
+            items: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Returns whether the item was not in the cache.
+    pub fn insert(&mut self, item: T) -> bool {
+        if !self.items.insert(item) {
+            return false;
+        }
+
+        self.order.push_back(item);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+}
+
+/// Something a peer sent that we had not seen before.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    Block(Block),
+    Tx(Transaction),
+}
+
+type BlockLookup =
+    dyn Fn(BlockHash) -> Pin<Box<dyn Future<Output = Option<Block>> + Send>> + Send + Sync;
+
+struct RelayState {
+    processed: SeenCache<InvItem>,
+    /// Items asked for, with the peer asked and when, forgotten if it
+    /// disconnects or times out.
+    requested: HashMap<InvItem, (SocketAddr, u64)>,
+    known_by_peer: HashMap<SocketAddr, SeenCache<InvItem>>,
+    recent_blocks: HashMap<BlockHash, Block>,
+    recent_order: VecDeque<BlockHash>,
+    /// Compact blocks waiting on transactions from the peer that sent them.
+    partial: HashMap<BlockHash, (SocketAddr, PartialBlock)>,
+    partial_order: VecDeque<BlockHash>,
+}
+
+impl RelayState {
+    fn peer(&mut self, address: &SocketAddr) -> &mut SeenCache<InvItem> {
+        self.known_by_peer
+            .entry(*address)
+            .or_insert_with(|| SeenCache::new(SEEN_CACHE_SIZE))
+    }
+
+    fn remember_block(&mut self, block: &Block) {
+        if self
+            .recent_blocks
+            .insert(block.get_hash(), block.clone())
+            .is_none()
+        {
+            self.recent_order.push_back(block.get_hash());
+        }
+        if self.recent_order.len() > RECENT_BLOCKS {
+            if let Some(oldest) = self.recent_order.pop_front() {
+                self.recent_blocks.remove(&oldest);
+            }
+        }
+    }
+
+    fn wait_for_txn(&mut self, from: &SocketAddr, partial: PartialBlock) {
+        let hash = partial.hash();
+        let partial_blocks = &self.partial;
+        self.partial_order
+            .retain(|waiting| *waiting != hash && partial_blocks.contains_key(waiting));
+
+        self.partial.insert(hash, (*from, partial));
+        self.partial_order.push_back(hash);
+        while self.partial_order.len() > MAX_PARTIAL_BLOCKS {
+            if let Some(oldest) = self.partial_order.pop_front() {
+                self.partial.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Inventory based relay on top of a `Transport`, usually a `Node`: new items are announced by
+/// hash and only fetched by peers that don't have them yet, so each peer
+/// receives the full data once no matter how many others announce it.
+/// Blocks are fetched as compact blocks, rebuilt from the mempool, and
+/// only requested in full when that fails.
+#[derive(Clone)]
+pub struct Relay<T: Transport = Node> {
+    node: T,
+    mempool: Arc<Mutex<Mempool>>,
+    state: Arc<Mutex<RelayState>>,
+    lookup: Arc<BlockLookup>,
+    events: EventBus,
+}
+
+impl<T: Transport> Relay<T> {
+    /// `lookup` finds blocks peers ask for that are no longer among the
+    /// recently relayed ones, usually by reading them from storage.
+    pub fn new<F>(
+        node: T,
+        mempool: Arc<Mutex<Mempool>>,
+        lookup: impl Fn(BlockHash) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = Option<Block>> + Send + 'static,
+    {
+        Relay {
+            node,
+            mempool,
+            state: Arc::new(Mutex::new(RelayState {
+                processed: SeenCache::new(SEEN_CACHE_SIZE),
+                requested: HashMap::new(),
+                known_by_peer: HashMap::new(),
+                recent_blocks: HashMap::new(),
+                recent_order: VecDeque::new(),
+                partial: HashMap::new(),
+                partial_order: VecDeque::new(),
+            })),
+            lookup: Arc::new(move |hash| Box::pin(lookup(hash))),
+            events: EventBus::new(),
+        }
+    }
+
+    /// Publishes transactions entering the mempool on `events`.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn node(&self) -> &T {
+        &self.node
+    }
+
+    pub fn mempool(&self) -> Arc<Mutex<Mempool>> {
+        self.mempool.clone()
+    }
+
+    /// Announces a block we mined or accepted, returns how many peers got the announcement.
+    pub fn broadcast_block(&self, block: &Block) -> usize {
+        self.state.lock().unwrap().remember_block(block);
+        self.announce(InvItem::Block(block.get_hash()))
+    }
+
+    pub fn broadcast_tx(&self, transaction: Transaction) -> usize {
+        let item = InvItem::Tx(transaction.hash());
+        if self.mempool.lock().unwrap().insert(transaction.clone()) {
+            self.events
+                .publish(ChainEvent::PendingTransaction(transaction));
+        }
+        self.announce(item)
+    }
+
+    /// Drops what we track for a peer, for transports that know when one
+    /// goes away.
+    pub fn forget_peer(&self, address: &SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.known_by_peer.remove(address);
+        state
+            .requested
+            .retain(|_, (requested_from, _)| requested_from != address);
+        state.partial.retain(|_, (sent_by, _)| sent_by != address);
+    }
+
+    fn announce(&self, item: InvItem) -> usize {
+        let connected = self.node.connected_peers();
+        let mut state = self.state.lock().unwrap();
+        state.processed.insert(item);
+        state
+            .known_by_peer
+            .retain(|address, _| connected.contains(address));
+        state
+            .requested
+            .retain(|_, (address, _)| connected.contains(address));
+        state
+            .partial
+            .retain(|_, (address, _)| connected.contains(address));
+
+        connected
+            .iter()
+            .filter(|address| state.peer(address).insert(item))
+            .filter(|address| self.node.send(address, Message::Inv(vec![item])).is_ok())
+            .count()
+    }
+
+    /// Handles relay messages from a peer. Returns new blocks, for the
+    /// caller to validate and `broadcast_block`, and new transactions,
+    /// which are already in the mempool and announced.
+    pub async fn handle(&self, from: &SocketAddr, message: Message) -> Option<Received> {
+        match message {
+            Message::Inv(items) | Message::GetData(items) if items.len() > MAX_INV_ITEMS => {
+                self.node.misbehaving(from, Misbehavior::Spam);
+                None
+            }
+            Message::Inv(items) => {
+                self.request_unknown(from, items);
+                None
+            }
+            Message::GetData(items) => {
+                self.serve(from, items).await;
+                None
+            }
+            Message::Block(block) => self.received_block(from, block),
+            Message::GetCompactBlock(hash) => {
+                self.serve_compact(from, hash).await;
+                None
+            }
+            Message::CompactBlock(compact) if compact.short_ids.len() > MAX_BLOCK_TXS => {
+                self.node.misbehaving(from, Misbehavior::MalformedMessage);
+                None
+            }
+            Message::GetBlockTxn { indexes, .. } if indexes.len() > MAX_BLOCK_TXS => {
+                self.node.misbehaving(from, Misbehavior::MalformedMessage);
+                None
+            }
+            // a header without its proof of work gets neither a request nor
+            // a slot among the partial blocks
+            Message::CompactBlock(compact) if !compact.header.has_valid_pow() => {
+                self.node.misbehaving(from, Misbehavior::InvalidBlock);
+                None
+            }
+            Message::CompactBlock(compact) => {
+                let item = InvItem::Block(compact.header.hash);
+                if self.state.lock().unwrap().processed.contains(&item) {
+                    return None;
+                }
+
+                let partial = PartialBlock::from_mempool(&compact, &self.mempool.lock().unwrap());
+                self.rebuilt(from, partial)
+            }
+            Message::GetBlockTxn { hash, indexes } => {
+                self.serve_block_txn(from, hash, indexes).await;
+                None
+            }
+            Message::BlockTxn { hash, transactions } => {
+                // only the peer asked may answer, or others could drop the
+                // request
+                let pending = {
+                    let mut state = self.state.lock().unwrap();
+                    match state.partial.get(&hash) {
+                        Some((sent_by, _)) if sent_by == from => state.partial.remove(&hash),
+                        _ => None,
+                    }
+                };
+                let (_, mut partial) = pending?;
+                if partial.fill(transactions) {
+                    return self.rebuilt(from, partial);
+                }
+
+                self.request_full(from, hash);
+                None
+            }
+            Message::Tx(transaction) => {
+                // the checks transactions submitted to us go through
+                if !transaction.has_valid_size() {
+                    self.node.misbehaving(from, Misbehavior::InvalidTransaction);
+                    return None;
+                }
+
+                let item = InvItem::Tx(transaction.hash());
+                if !self.first_time(from, item) {
+                    return None;
+                }
+
+                self.broadcast_tx(transaction.clone());
+                Some(Received::Tx(transaction))
+            }
+            _ => None,
+        }
+    }
+
+    fn received_block(&self, from: &SocketAddr, block: Block) -> Option<Received> {
+        // checked before the hash counts as seen, or a forged block would
+        // keep the real one out
+        if !block.has_valid_hash() {
+            self.node.misbehaving(from, Misbehavior::InvalidBlock);
+            return None;
+        }
+
+        let item = InvItem::Block(block.get_hash());
+        if !self.first_time(from, item) {
+            return None;
+        }
+
+        self.state.lock().unwrap().remember_block(&block);
+        Some(Received::Block(block))
+    }
+
+    /// Hands out the block if rebuilding it is done, asks for the missing
+    /// transactions otherwise, and for the full block if nothing is missing
+    /// but the result doesn't match the header.
+    fn rebuilt(&self, from: &SocketAddr, partial: PartialBlock) -> Option<Received> {
+        if let Some(block) = partial.block() {
+            return self.received_block(from, block);
+        }
+
+        let hash = partial.hash();
+        let indexes = partial.missing();
+        if indexes.is_empty() {
+            self.request_full(from, hash);
+            return None;
+        }
+
+        self.state.lock().unwrap().wait_for_txn(from, partial);
+        let _ = self.node.send(from, Message::GetBlockTxn { hash, indexes });
+        None
+    }
+
+    fn request_full(&self, from: &SocketAddr, hash: BlockHash) {
+        let _ = self
+            .node
+            .send(from, Message::GetData(vec![InvItem::Block(hash)]));
+    }
+
+    async fn find_block(&self, hash: &BlockHash) -> Option<Block> {
+        let recent = self.state.lock().unwrap().recent_blocks.get(hash).cloned();
+        match recent {
+            Some(block) => Some(block),
+            None => (self.lookup)(*hash).await,
+        }
+    }
+
+    async fn serve_compact(&self, from: &SocketAddr, hash: BlockHash) {
+        let block = match self.find_block(&hash).await {
+            Some(block) => block,
+            None => return,
+        };
+
+        self.state
+            .lock()
+            .unwrap()
+            .peer(from)
+            .insert(InvItem::Block(hash));
+        let message = match CompactBlock::new(&block) {
+            Some(compact) => Message::CompactBlock(compact),
+            None => Message::Block(block),
+        };
+        let _ = self.node.send(from, message);
+    }
+
+    async fn serve_block_txn(&self, from: &SocketAddr, hash: BlockHash, indexes: Vec<usize>) {
+        let transactions = match self
+            .find_block(&hash)
+            .await
+            .and_then(|block| block.transactions())
+        {
+            Some(transactions) => transactions,
+            None => return,
+        };
+
+        let requested: Option<Vec<Transaction>> = indexes
+            .iter()
+            .map(|index| transactions.get(*index).cloned())
+            .collect();
+        match requested {
+            Some(transactions) => {
+                let _ = self
+                    .node
+                    .send(from, Message::BlockTxn { hash, transactions });
+            }
+            None => {
+                self.node.misbehaving(from, Misbehavior::MalformedMessage);
+            }
+        }
+    }
+
+    fn first_time(&self, from: &SocketAddr, item: InvItem) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.peer(from).insert(item);
+        state.requested.remove(&item);
+        state.processed.insert(item)
+    }
+
+    fn request_unknown(&self, from: &SocketAddr, items: Vec<InvItem>) {
+        let now = self.node.now();
+        let mut state = self.state.lock().unwrap();
+        let mempool = self.mempool.lock().unwrap();
+        state
+            .requested
+            .retain(|_, (_, sent)| now < sent.saturating_add(REQUEST_TIMEOUT));
+
+        let wanted: Vec<InvItem> = items
+            .into_iter()
+            .filter(|item| {
+                state.peer(from).insert(*item);
+                let in_mempool = matches!(item, InvItem::Tx(hash) if mempool.contains(hash));
+
+                // a peer announcing again what we asked it for lost our request
+                let pending_elsewhere = state
+                    .requested
+                    .get(item)
+                    .is_some_and(|(asked, _)| asked != from);
+                let room =
+                    state.requested.len() < MAX_REQUESTED || state.requested.contains_key(item);
+                let wanted =
+                    !in_mempool && !state.processed.contains(item) && !pending_elsewhere && room;
+                if wanted {
+                    state.requested.insert(*item, (*from, now));
+                }
+                wanted
+            })
+            .collect();
+
+        let (blocks, transactions): (Vec<InvItem>, Vec<InvItem>) = wanted
+            .into_iter()
+            .partition(|item| matches!(item, InvItem::Block(_)));
+
+        for block in blocks {
+            if let InvItem::Block(hash) = block {
+                let _ = self.node.send(from, Message::GetCompactBlock(hash));
+            }
+        }
+        if !transactions.is_empty() {
+            let _ = self.node.send(from, Message::GetData(transactions));
+        }
+    }
+
+    async fn serve(&self, from: &SocketAddr, items: Vec<InvItem>) {
+        for item in items {
+            let message = match item {
+                InvItem::Block(hash) => self.find_block(&hash).await.map(Message::Block),
+                InvItem::Tx(hash) => self
+                    .mempool
+                    .lock()
+                    .unwrap()
+                    .get(&hash)
+                    .cloned()
+                    .map(Message::Tx),
+            };
+
+            if let Some(message) = message {
+                self.state.lock().unwrap().peer(from).insert(item);
+                let _ = self.node.send(from, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+    use tokio::time::{sleep, timeout};
+
+    use crate::blockchain::block::Block;
+    use crate::blockchain::transaction::{Transaction, MAX_TX_SIZE};
+    use crate::network::compact::test::block_with;
+    use crate::network::node::test::{start_node, wait_for};
+    use crate::network::relay::*;
+    use crate::simulation::transport::{Clock, Outbox, SimTransport};
+
+    /// A block with a hash of its own, at difficulty 0.
+    fn valid_block(data: &[u8]) -> Block {
+        Block {
+            hash: Block::calculate_hash(0, data, &[0; 32], 0, 0),
+            data: data.to_vec(),
+            ..Block::default()
+        }
+    }
+
+    async fn start_relay(
+        node_id: &str,
+        bootstrap: Vec<SocketAddr>,
+    ) -> (Relay, UnboundedReceiver<Received>) {
+        let (node, mut inbox) = start_node(node_id, bootstrap).await;
+        let relay = Relay::new(node, Arc::new(Mutex::new(Mempool::new())), |_| async {
+            None
+        });
+        let (sender, received) = unbounded_channel();
+
+        let handling = relay.clone();
+        tokio::spawn(async move {
+            while let Some((peer, message)) = inbox.recv().await {
+                if let Some(item) = handling.handle(&peer, message).await {
+                    if let Received::Block(block) = &item {
+                        handling.broadcast_block(block);
+                    }
+                    let _ = sender.send(item);
+                }
+            }
+        });
+
+        (relay, received)
+    }
+
+    #[test]
+    fn seen_cache_test() {
+        let mut cache = SeenCache::new(2);
+
+        assert!(cache.insert(1));
+        assert!(!cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(cache.insert(3));
+        assert!(!cache.contains(&1));
+        assert!(cache.contains(&3));
+    }
+
+    #[tokio::test]
+    async fn relays_each_item_once_test() {
+        let (first, mut first_received) = start_relay("first", vec![]).await;
+        let (second, mut second_received) =
+            start_relay("second", vec![first.node().listen_addr()]).await;
+        let (third, mut third_received) =
+            start_relay("third", vec![first.node().listen_addr()]).await;
+
+        assert!(
+            wait_for(|| [&first, &second, &third].iter().all(|relay| relay
+                .node()
+                .connected_peers()
+                .len()
+                == 2))
+            .await
+        );
+
+        let block = valid_block(b"relayed");
+        first.broadcast_block(&block);
+
+        let within = Duration::from_secs(10);
+        assert_eq!(
+            timeout(within, second_received.recv()).await.unwrap(),
+            Some(Received::Block(block.clone()))
+        );
+        assert_eq!(
+            timeout(within, third_received.recv()).await.unwrap(),
+            Some(Received::Block(block.clone()))
+        );
+
+        let transaction = Transaction::new(1, b"tx".to_vec());
+        third.broadcast_tx(transaction.clone());
+        assert_eq!(
+            timeout(within, first_received.recv()).await.unwrap(),
+            Some(Received::Tx(transaction.clone()))
+        );
+        assert_eq!(
+            timeout(within, second_received.recv()).await.unwrap(),
+            Some(Received::Tx(transaction.clone()))
+        );
+        assert!(second
+            .mempool()
+            .lock()
+            .unwrap()
+            .contains(&transaction.hash()));
+
+        sleep(Duration::from_millis(300)).await;
+        assert!(first_received.try_recv().is_err());
+        assert!(second_received.try_recv().is_err());
+        assert!(third_received.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn relays_compact_blocks_test() {
+        let (first, _) = start_relay("first", vec![]).await;
+        let (second, mut second_received) =
+            start_relay("second", vec![first.node().listen_addr()]).await;
+        assert!(wait_for(|| second.node().connected_peers().len() == 1).await);
+
+        let transactions: Vec<Transaction> =
+            (0..4).map(|i| Transaction::new(i, vec![i as u8])).collect();
+        for transaction in &transactions[..2] {
+            second.mempool().lock().unwrap().insert(transaction.clone());
+        }
+        let block = block_with(&transactions);
+
+        first.broadcast_block(&block);
+        let within = Duration::from_secs(10);
+        assert_eq!(
+            timeout(within, second_received.recv()).await.unwrap(),
+            Some(Received::Block(block))
+        );
+
+        let opaque = valid_block(b"not transactions");
+        first.broadcast_block(&opaque);
+        assert_eq!(
+            timeout(within, second_received.recv()).await.unwrap(),
+            Some(Received::Block(opaque))
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_block_is_dropped_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let relay = Relay::new(node, Arc::new(Mutex::new(Mempool::new())), |_| async {
+            None
+        });
+        let peer = SocketAddr::from(([127, 0, 0, 1], 3));
+        let block = valid_block(b"block");
+
+        assert!(relay
+            .handle(&peer, Message::Block(block.clone()))
+            .await
+            .is_some());
+        assert!(relay.handle(&peer, Message::Block(block)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn forged_block_does_not_shadow_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let relay = Relay::new(node, Arc::new(Mutex::new(Mempool::new())), |_| async {
+            None
+        });
+        let peer = SocketAddr::from(([127, 0, 0, 1], 3));
+        let block = valid_block(b"real");
+        let mut forged = block.clone();
+        forged.data = b"forged".to_vec();
+
+        assert!(relay.handle(&peer, Message::Block(forged)).await.is_none());
+        assert_eq!(
+            relay.handle(&peer, Message::Block(block.clone())).await,
+            Some(Received::Block(block))
+        );
+    }
+
+    #[tokio::test]
+    async fn request_timeout_test() {
+        let (clock, outbox) = (Clock::default(), Outbox::default());
+        let transport = SimTransport::new(
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            outbox.clone(),
+            clock.clone(),
+        );
+        let (first, second) = (
+            SocketAddr::from(([127, 0, 0, 1], 2)),
+            SocketAddr::from(([127, 0, 0, 1], 3)),
+        );
+        transport.set_peers(vec![first, second]);
+        let relay = Relay::new(transport, Arc::new(Mutex::new(Mempool::new())), |_| async {
+            None
+        });
+        let asked = || -> Vec<SocketAddr> {
+            outbox
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|(_, to, _)| to)
+                .collect()
+        };
+        let item = InvItem::Tx([7; 32]);
+
+        relay.handle(&first, Message::Inv(vec![item])).await;
+        assert_eq!(asked(), vec![first]);
+        relay.handle(&second, Message::Inv(vec![item])).await;
+        assert!(asked().is_empty());
+
+        // the first announcer never answered, the second one gets asked
+        clock.set(REQUEST_TIMEOUT * 1000);
+        relay.handle(&second, Message::Inv(vec![item])).await;
+        assert_eq!(asked(), vec![second]);
+
+        let items: Vec<InvItem> = (0..MAX_REQUESTED as u32 + 1)
+            .map(|i| {
+                let mut hash = [0; 32];
+                hash[..4].copy_from_slice(&i.to_be_bytes());
+                InvItem::Tx(hash)
+            })
+            .collect();
+        for chunk in items.chunks(MAX_INV_ITEMS) {
+            relay.handle(&first, Message::Inv(chunk.to_vec())).await;
+        }
+        assert_eq!(relay.state.lock().unwrap().requested.len(), MAX_REQUESTED);
+    }
+
+    #[tokio::test]
+    async fn invalid_transaction_test() {
+        let transport = SimTransport::new(
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            Outbox::default(),
+            Clock::default(),
+        );
+        let relay = Relay::new(
+            transport.clone(),
+            Arc::new(Mutex::new(Mempool::new())),
+            |_| async { None },
+        );
+        let peer = SocketAddr::from(([127, 0, 0, 1], 2));
+
+        for data in [vec![], vec![0; MAX_TX_SIZE + 1]] {
+            let transaction = Transaction::new(1, data);
+            assert!(relay
+                .handle(&peer, Message::Tx(transaction))
+                .await
+                .is_none());
+        }
+        assert_eq!(
+            transport.reported(),
+            vec![(peer, Misbehavior::InvalidTransaction); 2]
+        );
+        assert!(relay.mempool().lock().unwrap().is_empty());
+
+        let transaction = Transaction::new(1, vec![1; MAX_TX_SIZE]);
+        assert!(relay
+            .handle(&peer, Message::Tx(transaction))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn compact_block_without_pow_test() {
+        let outbox = Outbox::default();
+        let transport = SimTransport::new(
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            outbox.clone(),
+            Clock::default(),
+        );
+        let peer = SocketAddr::from(([127, 0, 0, 1], 2));
+        transport.set_peers(vec![peer]);
+        let relay = Relay::new(
+            transport.clone(),
+            Arc::new(Mutex::new(Mempool::new())),
+            |_| async { None },
+        );
+        let block = block_with(&[Transaction::new(1, vec![1])]);
+
+        let mut forged = CompactBlock::new(&block).unwrap();
+        forged.header.nonce += 1;
+        assert!(relay
+            .handle(&peer, Message::CompactBlock(forged))
+            .await
+            .is_none());
+        assert_eq!(
+            transport.reported(),
+            vec![(peer, Misbehavior::InvalidBlock)]
+        );
+        assert!(outbox.lock().unwrap().is_empty());
+        assert!(relay.state.lock().unwrap().partial.is_empty());
+
+        let compact = CompactBlock::new(&block).unwrap();
+        relay.handle(&peer, Message::CompactBlock(compact)).await;
+        assert!(matches!(
+            outbox.lock().unwrap()[..],
+            [(_, to, Message::GetBlockTxn { .. })] if to == peer
+        ));
+    }
+
+    #[tokio::test]
+    async fn partial_blocks_test() {
+        let (node, _) = start_node("node", vec![]).await;
+        let relay = Relay::new(node, Arc::new(Mutex::new(Mempool::new())), |_| async {
+            None
+        });
+        let peer = SocketAddr::from(([127, 0, 0, 1], 3));
+        let other = SocketAddr::from(([127, 0, 0, 1], 4));
+        let blocks: Vec<Block> = (0..=MAX_PARTIAL_BLOCKS as u64)
+            .map(|i| block_with(&[Transaction::new(i, vec![i as u8])]))
+            .collect();
+
+        for block in &blocks {
+            let compact = CompactBlock::new(block).unwrap();
+            assert!(relay
+                .handle(&peer, Message::CompactBlock(compact))
+                .await
+                .is_none());
+        }
+        let waiting = |hash: &BlockHash| relay.state.lock().unwrap().partial.contains_key(hash);
+        assert_eq!(
+            relay.state.lock().unwrap().partial.len(),
+            MAX_PARTIAL_BLOCKS
+        );
+        assert!(!waiting(&blocks[0].get_hash()));
+
+        let last = blocks.last().unwrap();
+        let answer = Message::BlockTxn {
+            hash: last.get_hash(),
+            transactions: last.transactions().unwrap(),
+        };
+        assert!(relay.handle(&other, answer.clone()).await.is_none());
+        assert!(waiting(&last.get_hash()));
+        assert_eq!(
+            relay.handle(&peer, answer).await,
+            Some(Received::Block(last.clone()))
+        );
+
+        let mut oversized = CompactBlock::new(&blocks[0]).unwrap();
+        oversized.short_ids = vec![oversized.short_ids[0]; MAX_BLOCK_TXS + 1];
+        assert!(relay
+            .handle(&peer, Message::CompactBlock(oversized))
+            .await
+            .is_none());
+        assert!(!waiting(&blocks[0].get_hash()));
+    }
+}
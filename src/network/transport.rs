@@ -0,0 +1,38 @@
+use anyhow::Result;
+use std::net::SocketAddr;
+
+use crate::network::message::Message;
+use crate::network::node::{unix_now, Node};
+use crate::network::peer::Misbehavior;
+
+/// What the relay needs from whatever carries messages between nodes: TCP
+/// through `Node`, or an in-memory network in simulations.
+pub trait Transport: Clone + Send + Sync + 'static {
+    fn connected_peers(&self) -> Vec<SocketAddr>;
+
+    fn send(&self, address: &SocketAddr, message: Message) -> Result<()>;
+
+    /// Scores the peer, returns whether that got it banned.
+    fn misbehaving(&self, address: &SocketAddr, kind: Misbehavior) -> bool;
+
+    /// Unix time in seconds, simulated in simulations.
+    fn now(&self) -> u64;
+}
+
+impl Transport for Node {
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        Node::connected_peers(self)
+    }
+
+    fn send(&self, address: &SocketAddr, message: Message) -> Result<()> {
+        Node::send(self, address, message)
+    }
+
+    fn misbehaving(&self, address: &SocketAddr, kind: Misbehavior) -> bool {
+        Node::misbehaving(self, address, kind)
+    }
+
+    fn now(&self) -> u64 {
+        unix_now()
+    }
+}
@@ -1,50 +1,232 @@
-use anyhow::Error;
-use rocket::get;
-use rocket::serde::json::Json;
+use rocket::serde::json::{json, Json};
+use rocket::serde::Serialize;
+use rocket::{get, FromFormField, State};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-use crate::blockchain::block::{Block, BlockHash};
-use crate::network::broadcast_block;
+use crate::blockchain::block::{Block, BlockHeader};
+use crate::blockchain::chain::Chain;
+use crate::blockchain::transaction::encode_transactions;
+use crate::network::node::unix_now;
+use crate::network::relay::Relay;
+use crate::server::error::{parse_hash, ApiError, ApiResult};
 use crate::storage::Client;
 
-type Result<T, E = rocket::response::Debug<Error>> = std::result::Result<T, E>;
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
 
 #[get("/block/number/<block_number>")]
-pub fn get_block_by_number(block_number: usize) -> Result<Json<Block>> {
-    let mut client = Client::default();
-    let block = client.get_block_by_number(block_number)?;
+pub async fn get_block_by_number(
+    block_number: usize,
+    client: &State<Client>,
+) -> ApiResult<Json<Block>> {
+    let block = client
+        .inner()
+        .clone()
+        .get_block_by_number(block_number)
+        .await?;
 
     Ok(Json(block))
 }
 
 #[get("/block/hash/<block_hash>")]
-pub fn get_block_by_hash(block_hash: String) -> Result<Json<Block>> {
-    let mut client = Client::default();
-    let mut final_hash = block_hash.clone();
+pub async fn get_block_by_hash(block_hash: &str, client: &State<Client>) -> ApiResult<Json<Block>> {
+    let hash = parse_hash(block_hash)?;
+    let block = client.inner().clone().get_block_by_hash(&hash).await?;
 
-    if (block_hash.chars().nth(1).unwrap() == 'x') {
-        let parts: Vec<&str> = block_hash.split('x').collect();
-        final_hash = String::from(parts[1]).to_lowercase();
+    Ok(Json(block))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, FromFormField)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+/// A page of the chain; pass `next` back as `from` to get the following one.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next: Option<usize>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum Entry {
+    Block(Block),
+    Header(BlockHeader),
+}
+
+/// Block numbers of a page between `from` and `to` (both inclusive, either
+/// way round depending on `order`), clamped to the tip.
+pub fn page_range(
+    tip: usize,
+    from: Option<usize>,
+    to: Option<usize>,
+    limit: usize,
+    order: Order,
+) -> Page<usize> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+
+    let (items, end): (Vec<usize>, usize) = match order {
+        Order::Asc => {
+            let end = to.unwrap_or(tip).min(tip);
+            let start = from.unwrap_or(0);
+            ((start..=end).take(limit).collect(), end)
+        }
+        Order::Desc => {
+            let end = to.unwrap_or(0);
+            let start = from.unwrap_or(tip).min(tip);
+            ((end..=start).rev().take(limit).collect(), end)
+        }
+    };
+
+    let next = match (order, items.last()) {
+        (Order::Asc, Some(&last)) if last < end => Some(last + 1),
+        (Order::Desc, Some(&last)) if last > end => Some(last - 1),
+        _ => None,
+    };
+
+    Page { items, next }
+}
+
+#[get("/blocks?<from>&<to>&<limit>&<order>&<headers>")]
+pub async fn get_blocks(
+    from: Option<usize>,
+    to: Option<usize>,
+    limit: Option<usize>,
+    order: Option<Order>,
+    headers: Option<bool>,
+    chain: &State<Arc<Mutex<Chain>>>,
+    client: &State<Client>,
+) -> ApiResult<Json<Page<Entry>>> {
+    if limit.is_some_and(|limit| limit > MAX_PAGE_SIZE) {
+        return Err(ApiError::bad_request("invalid_limit", "limit is too large")
+            .with_details(json!({ "max": MAX_PAGE_SIZE })));
     }
-    let block = client.get_block_by_str(&final_hash)?;
 
-    Ok(Json(block))
+    let tip = chain.lock().await.height();
+    let mut client = client.inner().clone();
+    let range = page_range(
+        tip,
+        from,
+        to,
+        limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        order.unwrap_or(Order::Asc),
+    );
+
+    let mut items = Vec::with_capacity(range.items.len());
+    for number in range.items {
+        items.push(match headers {
+            Some(true) => Entry::Header(client.get_header_by_number(number).await?),
+            _ => Entry::Block(client.get_block_by_number(number).await?),
+        });
+    }
+
+    Ok(Json(Page {
+        items,
+        next: range.next,
+    }))
 }
 
+#[get("/blocks/latest?<n>")]
+pub async fn get_latest_blocks(
+    n: Option<usize>,
+    chain: &State<Arc<Mutex<Chain>>>,
+    client: &State<Client>,
+) -> ApiResult<Json<Vec<Block>>> {
+    let tip = chain.lock().await.height();
+    let mut client = client.inner().clone();
+    let range = page_range(tip, None, None, n.unwrap_or(DEFAULT_PAGE_SIZE), Order::Desc);
+
+    let mut blocks = Vec::with_capacity(range.items.len());
+    for number in range.items {
+        blocks.push(client.get_block_by_number(number).await?);
+    }
+
+    Ok(Json(blocks))
+}
+
+/// The tip of the canonical chain.
 #[get("/latest")]
-pub fn get_latest_block() -> Result<Json<Block>> {
-    let mut client = Client::default();
-    let block = client.get_last_block()?;
+pub async fn get_latest_block(chain: &State<Arc<Mutex<Chain>>>) -> ApiResult<Json<Block>> {
+    let block = chain.lock().await.get_last_block().await?;
 
     Ok(Json(block))
 }
 
+/// Mines the pending pool into the next block, holding the chain only to
+/// read the tip and to add the block. `None` when another block took the
+/// tip while mining.
+pub async fn mine_pending(chain: &Mutex<Chain>, relay: &Relay) -> anyhow::Result<Option<Block>> {
+    let mempool = relay.mempool();
+    let transactions = mempool.lock().unwrap().transactions();
+
+    let template = chain
+        .lock()
+        .await
+        .next_block(unix_now(), encode_transactions(&transactions))
+        .await?;
+    let block = Chain::solve(template).await;
+    if !chain.lock().await.add_mined_block(&block).await? {
+        return Ok(None);
+    }
+
+    mempool.lock().unwrap().remove_included(&block);
+    relay.broadcast_block(&block);
+    Ok(Some(block))
+}
+
+/// Mines the pending pool into the next block.
 #[get("/mine")]
-pub fn mine_block() -> Result<Json<Block>> {
-    let mut db = Client::default();
-    let block = Block::default();
+pub async fn mine_block(
+    chain: &State<Arc<Mutex<Chain>>>,
+    relay: &State<Relay>,
+) -> ApiResult<Json<Block>> {
+    match mine_pending(chain, relay).await? {
+        Some(block) => Ok(Json(block)),
+        None => Err(ApiError::conflict(
+            "stale_tip",
+            "the tip moved while mining, try again",
+        )),
+    }
+}
 
-    db.save_block(&block)?;
-    broadcast_block(&block)?;
+#[cfg(test)]
+mod test {
+    use crate::server::block::*;
 
-    Ok(Json(block))
+    #[test]
+    fn page_range_test() {
+        let page = page_range(10, None, None, 4, Order::Asc);
+        assert_eq!(page.items, vec![0, 1, 2, 3]);
+        assert_eq!(page.next, Some(4));
+
+        let page = page_range(10, Some(8), None, 4, Order::Asc);
+        assert_eq!(page.items, vec![8, 9, 10]);
+        assert_eq!(page.next, None);
+
+        let page = page_range(10, None, Some(5), 4, Order::Desc);
+        assert_eq!(page.items, vec![10, 9, 8, 7]);
+        assert_eq!(page.next, Some(6));
+
+        let page = page_range(10, Some(6), Some(5), 4, Order::Desc);
+        assert_eq!(page.items, vec![6, 5]);
+        assert_eq!(page.next, None);
+    }
+
+    #[test]
+    fn page_range_bounds_test() {
+        let page = page_range(3, Some(2), Some(50), 0, Order::Asc);
+        assert_eq!(page.items, vec![2]);
+        assert_eq!(page.next, Some(3));
+
+        let page = page_range(500, None, None, 1000, Order::Asc);
+        assert_eq!(page.items.len(), MAX_PAGE_SIZE);
+
+        assert!(page_range(3, Some(5), None, 10, Order::Asc)
+            .items
+            .is_empty());
+    }
 }
@@ -0,0 +1,185 @@
+use anyhow::Error;
+use hex::decode;
+use redis::RedisError;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{Json, Value};
+use rocket::serde::Serialize;
+use rocket::{catch, catchers, Catcher, Request};
+
+use crate::storage::{NotFound, Pruned};
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+/// Error body every route answers with: a stable `code` to match on, a
+/// message for humans and optional details.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApiError {
+    #[serde(skip)]
+    pub status: Status,
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: &str, message: impl ToString) -> Self {
+        ApiError {
+            status,
+            code: code.to_string(),
+            message: message.to_string(),
+            details: None,
+        }
+    }
+
+    pub fn bad_request(code: &str, message: impl ToString) -> Self {
+        ApiError::new(Status::BadRequest, code, message)
+    }
+
+    pub fn not_found(message: impl ToString) -> Self {
+        ApiError::new(Status::NotFound, "not_found", message)
+    }
+
+    pub fn conflict(code: &str, message: impl ToString) -> Self {
+        ApiError::new(Status::Conflict, code, message)
+    }
+
+    pub fn unavailable(message: impl ToString) -> Self {
+        ApiError::new(Status::ServiceUnavailable, "storage_unavailable", message)
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+/// Missing keys become 404, pruned bodies 410 and Redis failures 503,
+/// anything else is a bug.
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        if err.is::<NotFound>() {
+            return ApiError::not_found(err);
+        }
+        if err.is::<Pruned>() {
+            return ApiError::new(Status::Gone, "pruned", err);
+        }
+        if err.is::<RedisError>() {
+            return ApiError::unavailable(err);
+        }
+
+        ApiError::new(Status::InternalServerError, "internal", err)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status;
+
+        Response::build_from(Json(self).respond_to(request)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Decodes hex with or without `0x`.
+pub fn parse_hex(value: &str) -> ApiResult<Vec<u8>> {
+    let value = value.strip_prefix("0x").unwrap_or(value);
+
+    decode(value).map_err(|err| {
+        ApiError::bad_request("invalid_hex", format!("invalid hex {:?}: {}", value, err))
+    })
+}
+
+/// A 32 byte block or transaction hash.
+pub fn parse_hash(value: &str) -> ApiResult<[u8; 32]> {
+    let bytes = parse_hex(value)?;
+
+    bytes.as_slice().try_into().map_err(|_| {
+        ApiError::bad_request(
+            "invalid_hash",
+            format!("hash must be 32 bytes, got {}", bytes.len()),
+        )
+    })
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, _request: &Request) -> ApiError {
+    let reason = status.reason().unwrap_or("Error");
+    let code = reason.to_lowercase().replace(' ', "_");
+
+    ApiError::new(status, &code, reason)
+}
+
+/// Answers requests no route handled (unknown paths, bodies that do not
+/// parse) with the same JSON errors.
+pub fn api_catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::error::*;
+    use anyhow::anyhow;
+    use rocket::local::blocking::Client;
+    use rocket::routes;
+
+    use crate::server::block::get_block_by_hash;
+    use crate::storage::{Client as Storage, MemoryStore};
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse_hex("0x0aff").unwrap(), vec![10, 255]);
+        assert_eq!(parse_hex("0aff").unwrap(), vec![10, 255]);
+        assert_eq!(parse_hex("0xzz").unwrap_err().code, "invalid_hex");
+
+        assert_eq!(parse_hash(&"ab".repeat(32)).unwrap(), [0xab; 32]);
+        assert_eq!(parse_hash("0x").unwrap_err().code, "invalid_hash");
+        assert_eq!(parse_hash("x").unwrap_err().status, Status::BadRequest);
+    }
+
+    #[test]
+    fn status_from_error_test() {
+        let missing = ApiError::from(Error::new(NotFound("key".to_string())));
+        assert_eq!(missing.status, Status::NotFound);
+        let pruned = ApiError::from(Error::new(Pruned(3)));
+        assert_eq!(
+            (pruned.status, pruned.code.as_str()),
+            (Status::Gone, "pruned")
+        );
+
+        let redis = ApiError::from(Error::new(RedisError::from((
+            redis::ErrorKind::IoError,
+            "connection refused",
+        ))));
+        assert_eq!(redis.status, Status::ServiceUnavailable);
+
+        let other = ApiError::from(anyhow!("broken"));
+        assert_eq!(other.status, Status::InternalServerError);
+    }
+
+    #[test]
+    fn json_body_test() {
+        let storage = Storage::memory("errors".to_string(), MemoryStore::default());
+        let rocket = rocket::build()
+            .manage(storage)
+            .register("/", api_catchers())
+            .mount("/", routes![get_block_by_hash]);
+        let client = Client::tracked(rocket).unwrap();
+
+        // used to panic on input shorter than two characters
+        for hash in ["0x12", "1", "0xnothex"] {
+            let response = client.get(format!("/block/hash/{}", hash)).dispatch();
+            assert_eq!(response.status(), Status::BadRequest);
+            let body: Value = response.into_json().unwrap();
+            assert!(body["code"].as_str().unwrap().starts_with("invalid_"));
+        }
+
+        let response = client.get("/nowhere").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body: Value = response.into_json().unwrap();
+        assert_eq!(body["code"], "not_found");
+    }
+}
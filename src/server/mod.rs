@@ -1 +1,176 @@
 pub mod block;
+pub mod error;
+pub mod peer;
+pub mod rpc;
+pub mod sse;
+pub mod status;
+pub mod tx;
+pub mod ws;
+
+use anyhow::Result;
+use rocket::data::{ByteUnit, Limits};
+use rocket::{routes, Build, Rocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+use crate::blockchain::chain::{BlockStatus, Chain};
+use crate::blockchain::events::EventBus;
+use crate::blockchain::mempool::Mempool;
+use crate::config::NodeConfig;
+use crate::network::node::{Node, NodeOptions};
+use crate::network::peer::Misbehavior;
+use crate::network::relay::{Received, Relay};
+use crate::server::block::{
+    get_block_by_hash, get_block_by_number, get_blocks, get_latest_block, get_latest_blocks,
+    mine_block, mine_pending,
+};
+use crate::server::error::api_catchers;
+use crate::server::peer::get_peers;
+use crate::server::sse::get_events;
+use crate::server::status::get_status;
+use crate::server::tx::{get_tx, post_data, post_tx, MAX_TX_SIZE};
+use crate::storage::Client;
+
+/// Request body limits letting the largest transaction through, raw or hex
+/// encoded in JSON. Raw bodies may go one byte over so that they get told
+/// the size limit.
+pub fn limits() -> Limits {
+    let encoded = ByteUnit::from(2 * MAX_TX_SIZE + 1024);
+
+    Limits::default()
+        .limit("bytes", ByteUnit::from(MAX_TX_SIZE + 1))
+        .limit("string", encoded)
+        .limit("json", encoded)
+}
+
+/// Syncs from the sync node, then builds the node as configured.
+pub async fn rocket(config: NodeConfig) -> Result<Rocket<Build>> {
+    let sync_handler = Chain::new(&config).await?.sync(&config).await?;
+    let storage = Client::from_config(&config).await?;
+
+    build(config, storage, Some(sync_handler)).await
+}
+
+/// Starts the peer to peer node and the WebSocket server on `storage`, and
+/// returns the HTTP API ready to launch. The chain is marked synced once
+/// `sync` finishes, right away without one.
+pub async fn build(
+    config: NodeConfig,
+    storage: Client,
+    sync: Option<JoinHandle<Chain>>,
+) -> Result<Rocket<Build>> {
+    let options = NodeOptions::from_config(&config, Some(storage.clone())).await;
+    let (node, mut inbox) = Node::start(options).await?;
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let events = EventBus::new();
+    let lookup = storage.clone();
+    let relay = Relay::new(node, mempool, move |hash| {
+        let mut client = lookup.clone();
+        async move { client.get_block_by_hash(&hash).await.ok() }
+    })
+    .with_events(events.clone());
+
+    let synced = match sync {
+        Some(sync_handler) => Some(sync_handler.await?),
+        None => None,
+    };
+
+    let mut chain = Chain::with_client(storage.clone()).await?;
+    match synced {
+        Some(synced) => {
+            chain.set_synced(synced.synced);
+            chain.sync_target = synced.sync_target;
+        }
+        None => chain.set_synced(true),
+    }
+    chain.set_block_time(config.block_time);
+    chain.set_prune(config.prune);
+    chain.prune().await?;
+    chain.set_events(events.clone());
+    let chain = Arc::new(AsyncMutex::new(chain));
+
+    let ws_addr = ws::start(config.ws_addr, events.clone()).await?;
+    println!("WEBSOCKET LISTENING ON {}", ws_addr);
+
+    let handling = relay.clone();
+    let receiving = chain.clone();
+    tokio::spawn(async move {
+        while let Some((peer, message)) = inbox.recv().await {
+            match handling.handle(&peer, message).await {
+                Some(Received::Block(block)) => {
+                    let status = receiving.lock().await.receive_block(&block).await;
+
+                    match status {
+                        Ok(BlockStatus::Extended | BlockStatus::Reorganized) => {
+                            println!("ADD BLOCK TO SYNCED CHAIN: {:?}", block);
+                            handling.mempool().lock().unwrap().remove_included(&block);
+                            handling.broadcast_block(&block);
+                        }
+                        Ok(BlockStatus::Invalid) => {
+                            handling
+                                .node()
+                                .misbehaving(&peer, Misbehavior::InvalidBlock);
+                        }
+                        Ok(_) => {}
+                        Err(err) => println!("COULD NOT STORE BLOCK: {}", err),
+                    }
+                }
+                Some(Received::Tx(transaction)) => {
+                    println!("NEW TRANSACTION: {:?}", transaction);
+                }
+                None => {}
+            }
+        }
+    });
+
+    if config.mine {
+        let mining = relay.clone();
+        let mining_chain = chain.clone();
+        let pause = Duration::from_millis(config.block_time.into());
+        tokio::spawn(async move {
+            loop {
+                sleep(pause).await;
+                match mine_pending(&mining_chain, &mining).await {
+                    Ok(Some(block)) => println!("MINED BLOCK {}", block.get_block_number()),
+                    Ok(None) => println!("MINED BLOCK WENT STALE, THE TIP MOVED"),
+                    Err(err) => println!("COULD NOT MINE BLOCK: {}", err),
+                }
+            }
+        });
+    }
+
+    let figment = rocket::Config::figment()
+        .merge(("address", config.http_addr.ip()))
+        .merge(("port", config.http_addr.port()))
+        .merge(("limits", limits()));
+    let rocket_res = rocket::custom(figment)
+        .manage(relay.node().clone())
+        .manage(relay)
+        .manage(chain)
+        .manage(events)
+        .manage(storage)
+        .register("/", api_catchers())
+        .mount(
+            "/",
+            routes![
+                get_block_by_hash,
+                get_block_by_number,
+                get_blocks,
+                get_latest_block,
+                get_latest_blocks,
+                mine_block,
+                get_peers,
+                get_status,
+                post_tx,
+                post_data,
+                get_tx,
+                rpc::rpc,
+                get_events
+            ],
+        );
+
+    Ok(rocket_res)
+}
@@ -0,0 +1,10 @@
+use rocket::serde::json::Json;
+use rocket::{get, State};
+
+use crate::network::node::Node;
+use crate::network::peer::PeerInfo;
+
+#[get("/peers")]
+pub fn get_peers(node: &State<Node>) -> Json<Vec<PeerInfo>> {
+    Json(node.peers())
+}
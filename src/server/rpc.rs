@@ -0,0 +1,414 @@
+use anyhow::Error;
+use hex::encode;
+use rocket::data::{Data, ToByteUnit};
+use rocket::response::status::NoContent;
+use rocket::serde::json::{from_str, from_value, json, Json, Value};
+use rocket::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use rocket::{post, Responder, State};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::blockchain::block::Block;
+use crate::blockchain::chain::Chain;
+use crate::blockchain::mempool::MAX_MEMPOOL_SIZE;
+use crate::network::relay::Relay;
+use crate::server::error::{parse_hash, parse_hex, ApiError};
+use crate::server::tx::submit;
+use crate::storage::NotFound;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// Errors of the node itself, e.g. storage being down or a rejected transaction.
+pub const SERVER_ERROR: i64 = -32000;
+/// Largest request body, enough for a full batch of large transactions.
+pub const MAX_RPC_SIZE: usize = 16 * 1024 * 1024;
+pub const MAX_BATCH_CALLS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+/// Bad input keeps its REST error code in `data`, everything else is the node's fault.
+impl From<ApiError> for RpcError {
+    fn from(err: ApiError) -> Self {
+        let code = match err.status.code {
+            400 => INVALID_PARAMS,
+            500 => INTERNAL_ERROR,
+            _ => SERVER_ERROR,
+        };
+
+        RpcError {
+            code,
+            message: err.message,
+            data: Some(json!({ "code": err.code, "details": err.details })),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        ApiError::from(err).into()
+    }
+}
+
+#[derive(Responder)]
+pub enum RpcResponse {
+    Reply(Json<Value>),
+    /// Only notifications were sent, there is nothing to answer.
+    Empty(NoContent),
+}
+
+/// What the methods run against, the same state the REST routes use.
+pub struct Rpc<'a> {
+    pub chain: &'a Mutex<Chain>,
+    pub relay: &'a Relay,
+}
+
+impl Rpc<'_> {
+    /// Answers a single call or a batch, `None` if nothing needs an answer.
+    pub async fn handle(&self, body: &str) -> Option<Value> {
+        let request: Value = match from_str(body) {
+            Ok(request) => request,
+            Err(err) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, err))),
+        };
+
+        match request {
+            Value::Array(calls) if calls.is_empty() => Some(error_response(
+                Value::Null,
+                RpcError::new(INVALID_REQUEST, "empty batch"),
+            )),
+            Value::Array(calls) if calls.len() > MAX_BATCH_CALLS => Some(error_response(
+                Value::Null,
+                RpcError::new(
+                    INVALID_REQUEST,
+                    format!(
+                        "batch of {} calls, at most {}",
+                        calls.len(),
+                        MAX_BATCH_CALLS
+                    ),
+                ),
+            )),
+            Value::Array(calls) => {
+                let mut responses = vec![];
+                for call in calls {
+                    responses.extend(self.handle_call(call).await);
+                }
+
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            call => self.handle_call(call).await,
+        }
+    }
+
+    async fn handle_call(&self, call: Value) -> Option<Value> {
+        // a null id is still answered, only a missing one makes a notification
+        let id = call.get("id").cloned();
+        let request = match from_value::<Request>(call) {
+            Ok(request) if request.jsonrpc == "2.0" => request,
+            _ => {
+                return Some(error_response(
+                    id.unwrap_or(Value::Null),
+                    RpcError::new(INVALID_REQUEST, "not a JSON-RPC 2.0 request"),
+                ))
+            }
+        };
+
+        let result = self.call(&request.method, request.params).await;
+        let id = id?;
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(err) => error_response(id, err),
+        })
+    }
+
+    pub async fn call(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        let params = match params {
+            Value::Null => vec![],
+            Value::Array(params) => params,
+            _ => return Err(RpcError::new(INVALID_PARAMS, "params must be an array")),
+        };
+
+        match method {
+            "getBlockCount" => Ok(json!(self.chain.lock().await.height())),
+            "getBestBlockHash" => {
+                let chain = self.chain.lock().await;
+                let tip = chain.hashes.last().copied().unwrap_or_default();
+                Ok(json!(format!("0x{}", encode(tip))))
+            }
+            "getBlockByNumber" => {
+                let number: usize = param(&params, 0)?;
+                found(
+                    self.chain
+                        .lock()
+                        .await
+                        .get_block_by_chain_index(number)
+                        .await,
+                )
+            }
+            "getBlockByHash" => {
+                let hash = parse_hash(&param::<String>(&params, 0)?)?;
+                found(self.chain.lock().await.get_block_by_hash(&hash).await)
+            }
+            "sendRawTransaction" => {
+                let data = parse_hex(&param::<String>(&params, 0)?)?;
+                Ok(json!(submit(self.relay, data)?.id))
+            }
+            "getMempoolInfo" => {
+                let mempool = self.relay.mempool();
+                let mempool = mempool.lock().unwrap();
+                let bytes: usize = mempool
+                    .transactions()
+                    .iter()
+                    .map(|transaction| transaction.data.len())
+                    .sum();
+
+                Ok(json!({ "size": mempool.len(), "bytes": bytes, "max_size": MAX_MEMPOOL_SIZE }))
+            }
+            "getPeerInfo" => Ok(json!(self.relay.node().peers())),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method {} not found", method),
+            )),
+        }
+    }
+}
+
+fn param<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<T, RpcError> {
+    let value = params.get(index).cloned().unwrap_or(Value::Null);
+
+    from_value(value)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, format!("param {}: {}", index, err)))
+}
+
+/// Missing blocks are `null`, like most chains answer them.
+fn found(block: anyhow::Result<Block>) -> Result<Value, RpcError> {
+    match block {
+        Ok(block) => Ok(json!(block)),
+        Err(err) if err.is::<NotFound>() => Ok(Value::Null),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+#[post("/rpc", data = "<body>")]
+pub async fn rpc(
+    body: Data<'_>,
+    chain: &State<Arc<Mutex<Chain>>>,
+    relay: &State<Relay>,
+) -> RpcResponse {
+    let body = match body.open(MAX_RPC_SIZE.bytes()).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        _ => {
+            return RpcResponse::Reply(Json(error_response(
+                Value::Null,
+                RpcError::new(
+                    INVALID_REQUEST,
+                    format!("request is larger than {} bytes", MAX_RPC_SIZE),
+                ),
+            )))
+        }
+    };
+    let rpc = Rpc { chain, relay };
+
+    match rpc.handle(&body).await {
+        Some(response) => RpcResponse::Reply(Json(response)),
+        None => RpcResponse::Empty(NoContent),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::mempool::Mempool;
+    use crate::network::node::test::start_node;
+    use crate::server::rpc::*;
+    use crate::server::tx::MAX_TX_SIZE;
+    use crate::storage::{Client as Storage, MemoryStore};
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    async fn rpc_state() -> (Mutex<Chain>, Relay) {
+        let client = Storage::memory("rpc".to_string(), MemoryStore::default());
+        let chain = Chain::with_client(client).await.unwrap();
+        let (node, _) = start_node("rpc", vec![]).await;
+        let mempool = Arc::new(std::sync::Mutex::new(Mempool::new()));
+        let relay = Relay::new(node, mempool, |_| async { None });
+
+        (Mutex::new(chain), relay)
+    }
+
+    #[tokio::test]
+    async fn call_test() {
+        let (chain, relay) = rpc_state().await;
+        let rpc = Rpc {
+            chain: &chain,
+            relay: &relay,
+        };
+
+        let response = rpc
+            .handle(r#"{"jsonrpc": "2.0", "method": "getBlockCount", "id": 1}"#)
+            .await
+            .unwrap();
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "result": 0, "id": 1 }));
+        let response = rpc
+            .handle(r#"{"jsonrpc": "2.0", "method": "getBlockCount", "id": null}"#)
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            json!({ "jsonrpc": "2.0", "result": 0, "id": null })
+        );
+        assert!(rpc
+            .handle(r#"{"jsonrpc": "2.0", "method": "getBlockCount"}"#)
+            .await
+            .is_none());
+
+        let block = rpc.call("getBlockByNumber", json!([0])).await.unwrap();
+        assert_eq!(block, json!(Block::genesis()));
+        let hash = rpc.call("getBestBlockHash", Value::Null).await.unwrap();
+        assert_eq!(
+            rpc.call("getBlockByHash", json!([hash])).await.unwrap(),
+            block
+        );
+        assert_eq!(
+            rpc.call("getBlockByNumber", json!([5])).await.unwrap(),
+            Value::Null
+        );
+
+        let id = rpc
+            .call("sendRawTransaction", json!(["0x0102"]))
+            .await
+            .unwrap();
+        assert_eq!(id.as_str().unwrap().len(), 66);
+        let info = rpc.call("getMempoolInfo", Value::Null).await.unwrap();
+        assert_eq!(info["size"], 1);
+        assert_eq!(info["bytes"], 2);
+    }
+
+    #[tokio::test]
+    async fn errors_and_batch_test() {
+        let (chain, relay) = rpc_state().await;
+        let rpc = Rpc {
+            chain: &chain,
+            relay: &relay,
+        };
+
+        let code = |response: Value| response["error"]["code"].as_i64().unwrap();
+        assert_eq!(code(rpc.handle("{nope").await.unwrap()), PARSE_ERROR);
+        assert_eq!(code(rpc.handle("[]").await.unwrap()), INVALID_REQUEST);
+        assert_eq!(
+            code(
+                rpc.handle(r#"{"method": "getBlockCount", "id": 1}"#)
+                    .await
+                    .unwrap()
+            ),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            code(
+                rpc.handle(r#"{"jsonrpc": "2.0", "method": "mine", "id": 1}"#)
+                    .await
+                    .unwrap()
+            ),
+            METHOD_NOT_FOUND
+        );
+        assert_eq!(
+            rpc.call("getBlockByHash", json!(["0x12"]))
+                .await
+                .unwrap_err()
+                .code,
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            rpc.call("getBlockByNumber", json!(["one"]))
+                .await
+                .unwrap_err()
+                .code,
+            INVALID_PARAMS
+        );
+
+        let batch = rpc
+            .handle(
+                r#"[
+                    {"jsonrpc": "2.0", "method": "getBlockCount", "id": 1},
+                    {"jsonrpc": "2.0", "method": "getMempoolInfo"},
+                    {"jsonrpc": "2.0", "method": "unknown", "id": "two"}
+                ]"#,
+            )
+            .await
+            .unwrap();
+        let batch = batch.as_array().unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0]["result"], 0);
+        assert_eq!(batch[1]["id"], "two");
+
+        assert_eq!(
+            rpc.handle(r#"{"jsonrpc": "2.0", "method": "getBlockCount"}"#)
+                .await,
+            None
+        );
+
+        let call = r#"{"jsonrpc": "2.0", "method": "getBlockCount", "id": 1}"#;
+        let batch = format!("[{}]", vec![call; MAX_BATCH_CALLS + 1].join(","));
+        assert_eq!(code(rpc.handle(&batch).await.unwrap()), INVALID_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn body_limit_test() {
+        let (chain, relay) = rpc_state().await;
+        let rocket = rocket::build()
+            .manage(Arc::new(chain))
+            .manage(relay)
+            .mount("/", routes![rpc]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        // a transaction past the 8 KiB Rocket takes for strings by default
+        let call = json!({
+            "jsonrpc": "2.0",
+            "method": "sendRawTransaction",
+            "params": [format!("0x{}", "01".repeat(MAX_TX_SIZE))],
+            "id": 1,
+        });
+        let response = client.post("/rpc").body(call.to_string()).dispatch().await;
+        let response: Value = response.into_json().await.unwrap();
+        assert!(response["result"].is_string());
+
+        let response = client
+            .post("/rpc")
+            .body(vec![b' '; MAX_RPC_SIZE + 1])
+            .dispatch()
+            .await;
+        let response: Value = response.into_json().await.unwrap();
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+    }
+}
@@ -0,0 +1,217 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::{json, Value};
+use rocket::{get, State};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+use hex::encode;
+
+use crate::blockchain::block::Block;
+use crate::blockchain::chain::Chain;
+use crate::blockchain::events::{ChainEvent, EventBus};
+
+/// Most blocks replayed on resume, older ones are left to `/blocks`.
+pub const MAX_REPLAY: usize = 1000;
+
+/// The `Last-Event-ID` header a reconnecting client sends, a block height.
+pub struct LastEventId(pub Option<usize>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|id| id.trim().parse().ok());
+
+        Outcome::Success(LastEventId(id))
+    }
+}
+
+/// Name, id and data of the SSE event for a chain event. Ids are block
+/// heights, a reorg taking the height it forked at so resuming replays the
+/// new branch. A mined block gets none, its `NewBlock` carries the height.
+pub fn sse_parts(event: &ChainEvent) -> Option<(&'static str, Option<usize>, Value)> {
+    match event {
+        ChainEvent::NewBlock(block) => Some((
+            "block-accepted",
+            Some(block.get_block_number()),
+            json!(block),
+        )),
+        ChainEvent::MinedBlock(block) => Some(("mined-block", None, json!(block))),
+        ChainEvent::BlockRejected { hash, reason } => Some((
+            "block-rejected",
+            None,
+            json!({ "hash": format!("0x{}", encode(hash)), "reason": reason }),
+        )),
+        ChainEvent::Reorg { fork_height, .. } => {
+            Some(("reorg", Some(*fork_height), json!(event)["data"].take()))
+        }
+        ChainEvent::NewHead(_) | ChainEvent::PendingTransaction(_) => None,
+    }
+}
+
+fn sse_event(event: &ChainEvent) -> Option<Event> {
+    let (name, id, data) = sse_parts(event)?;
+    let sse = Event::json(&data).event(name);
+
+    Some(match id {
+        Some(id) => sse.id(id.to_string()),
+        None => sse,
+    })
+}
+
+/// What a resuming client missed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Replay {
+    /// Heights from the first one missed up to the first one replayed, when
+    /// they could not be sent: past `MAX_REPLAY`, pruned or unreadable.
+    pub skipped: Option<(usize, usize)>,
+    pub blocks: Vec<Block>,
+}
+
+/// Main chain blocks above `last_height`, at most the `MAX_REPLAY` newest.
+/// Replayed blocks always follow each other up to the tip, a block that
+/// cannot be read drops those before it.
+pub async fn replay(chain: &mut Chain, last_height: usize) -> Replay {
+    let missed_from = last_height.saturating_add(1);
+    let from = missed_from.max(chain.hashes.len().saturating_sub(MAX_REPLAY));
+
+    let (mut blocks, mut resume_from) = (vec![], from);
+    for height in from..chain.hashes.len() {
+        match chain.get_block_by_chain_index(height).await {
+            Ok(block) => blocks.push(block),
+            Err(_) => {
+                blocks.clear();
+                resume_from = height + 1;
+            }
+        }
+    }
+
+    Replay {
+        skipped: (missed_from < resume_from).then_some((missed_from, resume_from)),
+        blocks,
+    }
+}
+
+/// Tells a resuming client which blocks it has to fetch some other way.
+fn reset_event((missed_from, resume_from): (usize, usize)) -> Event {
+    Event::json(&json!({ "missed_from": missed_from, "resume_from": resume_from })).event("reset")
+}
+
+#[get("/events")]
+pub async fn get_events(
+    last_event_id: LastEventId,
+    events: &State<EventBus>,
+    chain: &State<Arc<Mutex<Chain>>>,
+) -> EventStream![] {
+    // subscribe before replaying so nothing falls in between
+    let mut receiver = events.subscribe();
+    let missed = match last_event_id.0 {
+        Some(height) => replay(&mut *chain.lock().await, height).await,
+        None => Replay::default(),
+    };
+    let replayed: HashSet<_> = missed.blocks.iter().map(Block::get_hash).collect();
+
+    EventStream! {
+        if let Some(skipped) = missed.skipped {
+            yield reset_event(skipped);
+        }
+        for block in missed.blocks {
+            if let Some(event) = sse_event(&ChainEvent::NewBlock(block)) {
+                yield event;
+            }
+        }
+
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+
+            if matches!(&event, ChainEvent::NewBlock(block) if replayed.contains(&block.get_hash())) {
+                continue;
+            }
+            if let Some(event) = sse_event(&event) {
+                yield event;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::chain::MIN_PRUNE_KEEP;
+    use crate::server::sse::*;
+    use crate::storage::{Client, MemoryStore};
+
+    #[test]
+    fn sse_parts_test() {
+        let genesis = Block::genesis();
+
+        let (name, id, data) = sse_parts(&ChainEvent::NewBlock(genesis.clone())).unwrap();
+        assert_eq!((name, id), ("block-accepted", Some(0)));
+        assert_eq!(data, json!(genesis));
+
+        let reorg = ChainEvent::Reorg {
+            old_tip: [1; 32],
+            new_tip: [2; 32],
+            fork_height: 4,
+            depth: 2,
+        };
+        let (name, id, data) = sse_parts(&reorg).unwrap();
+        assert_eq!((name, id), ("reorg", Some(4)));
+        assert_eq!(data["depth"], 2);
+
+        let rejected = ChainEvent::BlockRejected {
+            hash: [0; 32],
+            reason: "bad".to_string(),
+        };
+        assert_eq!(sse_parts(&rejected).unwrap().1, None);
+        let mined = sse_parts(&ChainEvent::MinedBlock(genesis.clone())).unwrap();
+        assert_eq!((mined.0, mined.1), ("mined-block", None));
+        assert!(sse_parts(&ChainEvent::NewHead(genesis.header())).is_none());
+    }
+
+    #[tokio::test]
+    async fn replay_test() -> anyhow::Result<()> {
+        let client = Client::memory("sse".to_string(), MemoryStore::default());
+        let mut chain = Chain::with_client(client).await?;
+        for timestamp in 1..=3 {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        let missed = replay(&mut chain, 1).await;
+        let heights: Vec<usize> = missed.blocks.iter().map(Block::get_block_number).collect();
+        assert_eq!(heights, vec![2, 3]);
+        assert_eq!(missed.skipped, None);
+        assert_eq!(replay(&mut chain, 3).await, Replay::default());
+        assert_eq!(replay(&mut chain, 10).await, Replay::default());
+        assert_eq!(replay(&mut chain, usize::MAX).await, Replay::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replay_pruned_test() -> anyhow::Result<()> {
+        let client = Client::memory("sse".to_string(), MemoryStore::default());
+        let mut chain = Chain::with_client(client).await?;
+        chain.set_prune(Some(MIN_PRUNE_KEEP));
+        for timestamp in 1..=15 {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        // the bodies of 1 to 5 are pruned
+        let missed = replay(&mut chain, 2).await;
+        assert_eq!(missed.skipped, Some((3, 6)));
+        assert_eq!(missed.blocks.len(), 10);
+        assert_eq!(missed.blocks[0].get_block_number(), 6);
+        assert_eq!(replay(&mut chain, 5).await.skipped, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::{get, State};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::blockchain::chain::Chain;
+use crate::network::relay::Relay;
+use crate::server::error::ApiResult;
+use crate::storage::cache::CacheStats;
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Status {
+    pub node_id: String,
+    pub height: usize,
+    pub tip: String,
+    /// Decimal string, as it does not fit in a JSON number.
+    pub total_work: String,
+    /// Difficulty the next block will be mined at.
+    pub difficulty: u32,
+    pub synced: bool,
+    pub sync_progress: f64,
+    pub peers: usize,
+    pub mempool: usize,
+    /// Block cache hits, misses and size.
+    pub cache: CacheStats,
+}
+
+#[get("/status")]
+pub async fn get_status(
+    chain: &State<Arc<Mutex<Chain>>>,
+    relay: &State<Relay>,
+) -> ApiResult<Json<Status>> {
+    let mut chain = chain.lock().await;
+    let tip = chain.get_last_block().await?;
+
+    Ok(Json(Status {
+        node_id: relay.node().node_id(),
+        height: chain.height(),
+        tip: format!("0x{}", hex::encode(tip.get_hash())),
+        total_work: chain.total_work().to_string(),
+        difficulty: chain.get_difficulty().await?,
+        synced: chain.synced,
+        sync_progress: chain.sync_progress(),
+        peers: relay.node().connected_peers().len(),
+        mempool: relay.mempool().lock().unwrap().len(),
+        cache: chain.cache_stats(),
+    }))
+}
@@ -0,0 +1,181 @@
+use anyhow::Error;
+use hex::encode;
+use rocket::serde::json::{json, Json};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{get, post, State};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::blockchain::chain::Chain;
+pub use crate::blockchain::transaction::MAX_TX_SIZE;
+use crate::blockchain::transaction::{Transaction, TxHash};
+use crate::network::node::unix_now;
+use crate::network::relay::Relay;
+use crate::server::error::{parse_hash, parse_hex, ApiError, ApiResult};
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxRequest {
+    /// Hex encoded payload, with or without `0x`.
+    pub data: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde", tag = "status", rename_all = "lowercase")]
+pub enum TxStatus {
+    Pending,
+    Included {
+        block_hash: String,
+        block_number: usize,
+    },
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxReceipt {
+    pub id: String,
+    #[serde(flatten)]
+    pub status: TxStatus,
+}
+
+pub fn validate_data(data: &[u8]) -> ApiResult<()> {
+    if data.is_empty() {
+        return Err(ApiError::bad_request(
+            "invalid_transaction",
+            "transaction data is empty",
+        ));
+    }
+    if data.len() > MAX_TX_SIZE {
+        return Err(ApiError::bad_request(
+            "invalid_transaction",
+            format!("transaction data is {} bytes", data.len()),
+        )
+        .with_details(json!({ "max_size": MAX_TX_SIZE })));
+    }
+
+    Ok(())
+}
+
+pub async fn tx_status(chain: &mut Chain, relay: &Relay, hash: &TxHash) -> Result<TxStatus, Error> {
+    if let Some(block) = chain.find_transaction(hash).await? {
+        return Ok(TxStatus::Included {
+            block_hash: format!("0x{}", encode(block.get_hash())),
+            block_number: block.get_block_number(),
+        });
+    }
+
+    match relay.mempool().lock().unwrap().contains(hash) {
+        true => Ok(TxStatus::Pending),
+        false => Ok(TxStatus::Unknown),
+    }
+}
+
+/// Validates `data` and puts it in the pending pool as a new transaction.
+pub fn submit(relay: &Relay, data: Vec<u8>) -> ApiResult<TxReceipt> {
+    validate_data(&data)?;
+
+    let transaction = Transaction::new(unix_now(), data);
+    let id = format!("0x{}", encode(transaction.hash()));
+    if relay
+        .mempool()
+        .lock()
+        .unwrap()
+        .contains(&transaction.hash())
+    {
+        return Err(
+            ApiError::conflict("already_pending", "transaction is already pending")
+                .with_details(json!({ "id": id })),
+        );
+    }
+    relay.broadcast_tx(transaction);
+
+    Ok(TxReceipt {
+        id,
+        status: TxStatus::Pending,
+    })
+}
+
+#[post("/tx", format = "json", data = "<request>")]
+pub fn post_tx(request: Json<TxRequest>, relay: &State<Relay>) -> ApiResult<Json<TxReceipt>> {
+    Ok(Json(submit(relay, parse_hex(&request.data)?)?))
+}
+
+/// Takes the request body as it is, for clients treating blocks as opaque data.
+#[post("/data", data = "<data>")]
+pub fn post_data(data: Vec<u8>, relay: &State<Relay>) -> ApiResult<Json<TxReceipt>> {
+    Ok(Json(submit(relay, data)?))
+}
+
+#[get("/tx/<id>")]
+pub async fn get_tx(
+    id: &str,
+    chain: &State<Arc<Mutex<Chain>>>,
+    relay: &State<Relay>,
+) -> ApiResult<Json<TxReceipt>> {
+    let hash: TxHash = parse_hash(id)?;
+    let status = tx_status(&mut *chain.lock().await, relay, &hash).await?;
+
+    Ok(Json(TxReceipt {
+        id: format!("0x{}", encode(hash)),
+        status,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::server::tx::*;
+    use rocket::http::{ContentType, Status};
+    use rocket::local::asynchronous::Client;
+    use rocket::routes;
+
+    use crate::blockchain::mempool::Mempool;
+    use crate::network::node::test::start_node;
+    use crate::server::error::api_catchers;
+    use crate::server::limits;
+
+    #[test]
+    fn validate_test() {
+        assert!(validate_data(b"payload").is_ok());
+        assert!(validate_data(b"").is_err());
+        assert!(validate_data(&vec![0; MAX_TX_SIZE + 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn large_transactions_test() {
+        let (node, _) = start_node("tx", vec![]).await;
+        let mempool = Arc::new(std::sync::Mutex::new(Mempool::new()));
+        let relay = Relay::new(node, mempool, |_| async { None });
+        let figment = rocket::Config::figment().merge(("limits", limits()));
+        let rocket = rocket::custom(figment)
+            .manage(relay)
+            .register("/", api_catchers())
+            .mount("/", routes![post_tx, post_data]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        // past the 8 KiB Rocket takes by default
+        let response = client
+            .post("/data")
+            .body(vec![1; MAX_TX_SIZE])
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let hex = format!(r#"{{"data": "0x{}"}}"#, "02".repeat(MAX_TX_SIZE));
+        let response = client
+            .post("/tx")
+            .header(ContentType::JSON)
+            .body(hex)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .post("/data")
+            .body(vec![3; MAX_TX_SIZE + 1])
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: rocket::serde::json::Value = response.into_json().await.unwrap();
+        assert_eq!(body["details"]["max_size"], MAX_TX_SIZE);
+    }
+}
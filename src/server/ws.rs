@@ -0,0 +1,235 @@
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use rocket::serde::json::{from_str, json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::blockchain::events::{ChainEvent, EventBus};
+use crate::server::rpc::{
+    RpcError, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR, SERVER_ERROR,
+};
+
+pub const DEFAULT_WS_ADDR: &str = "127.0.0.1:8001";
+/// Most subscriptions a connection may hold at once.
+pub const MAX_SUBSCRIPTIONS: usize = 32;
+pub const TOPICS: [&str; 6] = [
+    "newHeads",
+    "newBlocks",
+    "pendingTransactions",
+    "reorg",
+    "minedBlock",
+    "blockRejected",
+];
+
+/// Listens for WebSocket clients on `addr`, each one getting the events of
+/// the topics it subscribes to. Returns the address actually bound.
+pub async fn start(addr: SocketAddr, events: EventBus) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let events = events.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve(stream, events).await {
+                    println!("WEBSOCKET CLOSED: {}", err);
+                }
+            });
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn serve(stream: TcpStream, events: EventBus) -> Result<()> {
+    let mut socket = accept_async(stream).await?;
+    let mut receiver = events.subscribe();
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    let reply = subscriptions.handle(&text);
+                    socket.send(Message::Text(reply.to_string())).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+            },
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    for notification in subscriptions.notifications(&event) {
+                        socket.send(Message::Text(notification.to_string())).await?;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => println!("WEBSOCKET MISSED {} EVENTS", missed),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Topics a connection subscribed to, driven by JSON-RPC `subscribe` and
+/// `unsubscribe` calls.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    next_id: u64,
+    topics: BTreeMap<u64, &'static str>,
+}
+
+impl Subscriptions {
+    pub fn handle(&mut self, text: &str) -> Value {
+        let request: Value = match from_str(text) {
+            Ok(request) => request,
+            Err(err) => return reply(Value::Null, Err(RpcError::new(PARSE_ERROR, err))),
+        };
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let param = request.get("params").and_then(|params| params.get(0));
+
+        let result = match request.get("method").and_then(Value::as_str) {
+            Some("subscribe") => self.subscribe(param),
+            Some("unsubscribe") => self.unsubscribe(param),
+            Some(method) => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method {} not found", method),
+            )),
+            None => Err(RpcError::new(INVALID_REQUEST, "missing method")),
+        };
+
+        reply(id, result)
+    }
+
+    fn subscribe(&mut self, topic: Option<&Value>) -> Result<Value, RpcError> {
+        let topic = topic.and_then(Value::as_str).unwrap_or_default();
+        let topic = TOPICS
+            .into_iter()
+            .find(|known| *known == topic)
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("unknown topic {:?}", topic)))?;
+        if self.topics.len() >= MAX_SUBSCRIPTIONS {
+            return Err(RpcError::new(
+                SERVER_ERROR,
+                format!("at most {} subscriptions per connection", MAX_SUBSCRIPTIONS),
+            ));
+        }
+
+        self.next_id += 1;
+        self.topics.insert(self.next_id, topic);
+        Ok(json!(subscription_id(self.next_id)))
+    }
+
+    fn unsubscribe(&mut self, id: Option<&Value>) -> Result<Value, RpcError> {
+        let id = id
+            .and_then(Value::as_str)
+            .and_then(|id| id.strip_prefix("0x"))
+            .and_then(|id| u64::from_str_radix(id, 16).ok())
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "invalid subscription id"))?;
+
+        Ok(json!(self.topics.remove(&id).is_some()))
+    }
+
+    pub fn notifications(&self, event: &ChainEvent) -> Vec<Value> {
+        let data = json!(event)["data"].take();
+
+        self.topics
+            .iter()
+            .filter(|(_, topic)| **topic == event.topic())
+            .map(|(id, topic)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "subscription",
+                    "params": {
+                        "subscription": subscription_id(*id),
+                        "topic": topic,
+                        "result": data,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+fn subscription_id(id: u64) -> String {
+    format!("0x{:x}", id)
+}
+
+fn reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error, "id": id }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::block::Block;
+    use crate::server::ws::*;
+    use tokio_tungstenite::connect_async;
+
+    fn call(method: &str, param: Value) -> String {
+        json!({ "jsonrpc": "2.0", "method": method, "params": [param], "id": 1 }).to_string()
+    }
+
+    #[test]
+    fn subscriptions_test() {
+        let mut subscriptions = Subscriptions::default();
+
+        let heads = subscriptions.handle(&call("subscribe", json!("newHeads")));
+        assert_eq!(heads["result"], "0x1");
+        let unknown = subscriptions.handle(&call("subscribe", json!("everything")));
+        assert_eq!(unknown["error"]["code"], INVALID_PARAMS);
+
+        let genesis = Block::genesis();
+        let notifications = subscriptions.notifications(&ChainEvent::NewHead(genesis.header()));
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"]["subscription"], "0x1");
+        assert_eq!(
+            notifications[0]["params"]["result"],
+            json!(genesis.header())
+        );
+        assert!(subscriptions
+            .notifications(&ChainEvent::NewBlock(genesis.clone()))
+            .is_empty());
+
+        let removed = subscriptions.handle(&call("unsubscribe", json!("0x1")));
+        assert_eq!(removed["result"], true);
+        assert!(subscriptions
+            .notifications(&ChainEvent::NewHead(genesis.header()))
+            .is_empty());
+
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            let reply = subscriptions.handle(&call("subscribe", json!("reorg")));
+            assert!(reply["result"].is_string());
+        }
+        let reply = subscriptions.handle(&call("subscribe", json!("reorg")));
+        assert_eq!(reply["error"]["code"], SERVER_ERROR);
+        assert_eq!(subscriptions.topics.len(), MAX_SUBSCRIPTIONS);
+    }
+
+    #[tokio::test]
+    async fn websocket_test() {
+        let events = EventBus::new();
+        let addr = start("127.0.0.1:0".parse().unwrap(), events.clone())
+            .await
+            .unwrap();
+        let (mut socket, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+
+        socket
+            .send(Message::Text(call("subscribe", json!("newBlocks"))))
+            .await
+            .unwrap();
+        let reply = socket.next().await.unwrap().unwrap();
+        let reply: Value = from_str(reply.to_text().unwrap()).unwrap();
+        assert_eq!(reply["result"], "0x1");
+
+        events.publish(ChainEvent::NewBlock(Block::genesis()));
+        let notification = socket.next().await.unwrap().unwrap();
+        let notification: Value = from_str(notification.to_text().unwrap()).unwrap();
+        assert_eq!(notification["params"]["topic"], "newBlocks");
+        assert_eq!(notification["params"]["result"], json!(Block::genesis()));
+    }
+}
@@ -0,0 +1,525 @@
+pub mod transport;
+
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::block::{Block, BlockHash};
+use crate::blockchain::chain::{BlockStatus, Chain};
+use crate::blockchain::mempool::Mempool;
+use crate::blockchain::transaction::{encode_transactions, Transaction};
+use crate::network::message::{InvItem, Message};
+use crate::network::peer::Misbehavior;
+use crate::network::relay::{Received, Relay};
+use crate::network::transport::Transport;
+use crate::simulation::transport::{Clock, Outbox, SimTransport};
+use crate::storage::{Client, MemoryStore};
+
+pub const SIM_BASE_PORT: u16 = 9000;
+
+pub struct SimConfig {
+    pub seed: u64,
+    pub nodes: usize,
+    /// Delivery delay in milliseconds, picked uniformly in this range.
+    pub min_latency: u64,
+    pub max_latency: u64,
+    /// Chance, from 0 to 1, of any message getting lost.
+    pub loss: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 0,
+            nodes: 4,
+            min_latency: 10,
+            max_latency: 100,
+            loss: 0.0,
+        }
+    }
+}
+
+/// xorshift64*, enough to make every run with the same seed identical.
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng((seed ^ 0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// A number in `0..bound`.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound.max(1)
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 <= probability && probability > 0.0
+    }
+}
+
+pub struct SimNode {
+    pub id: usize,
+    pub chain: Chain,
+    pub relay: Relay<SimTransport>,
+}
+
+impl SimNode {
+    pub fn address(&self) -> SocketAddr {
+        self.relay.node().address()
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        *self.chain.hashes.last().unwrap()
+    }
+
+    pub fn height(&self) -> usize {
+        self.chain.hashes.len() - 1
+    }
+
+    pub fn mempool_len(&self) -> usize {
+        self.relay.mempool().lock().unwrap().len()
+    }
+}
+
+struct Envelope {
+    deliver_at: u64,
+    seq: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    message: Message,
+}
+
+impl PartialEq for Envelope {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deliver_at, self.seq) == (other.deliver_at, other.seq)
+    }
+}
+
+impl Eq for Envelope {}
+
+impl PartialOrd for Envelope {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Envelope {
+    // reversed, so the heap pops the earliest message first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+
+/// Many nodes in one process, each with its own `Chain` on a memory store
+/// and a `Relay` over an in-memory transport. Time only moves when the
+/// simulation delivers messages or is told to advance, and latency, loss
+/// and partitions come from the seeded rng, so a seed always replays the
+/// same run.
+pub struct Simulation {
+    config: SimConfig,
+    rng: SimRng,
+    clock: Clock,
+    seq: u64,
+    nodes: Vec<SimNode>,
+    groups: Vec<usize>,
+    queue: BinaryHeap<Envelope>,
+    outbox: Outbox,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+impl Simulation {
+    pub async fn new(config: SimConfig) -> Result<Self> {
+        let outbox: Outbox = Arc::new(Mutex::new(vec![]));
+        let clock = Clock::default();
+        let mut nodes = vec![];
+
+        for id in 0..config.nodes {
+            let store = MemoryStore::default();
+            let chain = Chain::with_client(Client::memory(id.to_string(), store.clone())).await?;
+            let address = SocketAddr::from(([127, 0, 0, 1], SIM_BASE_PORT + id as u16));
+            let transport = SimTransport::new(address, outbox.clone(), clock.clone());
+            let relay = Relay::new(
+                transport,
+                Arc::new(Mutex::new(Mempool::new())),
+                move |hash| {
+                    let mut client = Client::memory(id.to_string(), store.clone());
+                    async move { client.get_block_by_hash(&hash).await.ok() }
+                },
+            );
+
+            nodes.push(SimNode { id, chain, relay });
+        }
+
+        let mut simulation = Simulation {
+            rng: SimRng::new(config.seed),
+            groups: vec![0; config.nodes],
+            config,
+            clock,
+            seq: 0,
+            nodes,
+            queue: BinaryHeap::new(),
+            outbox,
+            delivered: 0,
+            dropped: 0,
+        };
+        simulation.connect_groups();
+
+        Ok(simulation)
+    }
+
+    /// Milliseconds since the simulation started.
+    pub fn now(&self) -> u64 {
+        self.clock.millis()
+    }
+
+    pub fn node(&self, id: usize) -> &SimNode {
+        &self.nodes[id]
+    }
+
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+
+    pub fn advance(&mut self, millis: u64) {
+        self.clock.set(self.now() + millis);
+    }
+
+    /// Splits the nodes so that only nodes in the same group can talk,
+    /// nodes left out end up alone. Messages already on their way across
+    /// the new boundaries are lost.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        self.groups = (0..self.nodes.len()).map(|id| groups.len() + id).collect();
+        for (group, members) in groups.iter().enumerate() {
+            for id in members.iter() {
+                self.groups[*id] = group;
+            }
+        }
+
+        self.connect_groups();
+    }
+
+    /// Reconnects everyone and has every node announce its tip.
+    pub async fn heal(&mut self) -> Result<()> {
+        self.groups = vec![0; self.nodes.len()];
+        self.connect_groups();
+
+        for node in self.nodes.iter_mut() {
+            let tip = node.chain.get_last_block().await?;
+            node.relay.broadcast_block(&tip);
+        }
+        self.flush();
+        Ok(())
+    }
+
+    fn connect_groups(&mut self) {
+        let addresses: Vec<SocketAddr> = self.nodes.iter().map(SimNode::address).collect();
+
+        for node in &self.nodes {
+            let peers: Vec<SocketAddr> = addresses
+                .iter()
+                .enumerate()
+                .filter(|(id, _)| *id != node.id && self.groups[*id] == self.groups[node.id])
+                .map(|(_, address)| *address)
+                .collect();
+
+            for address in &addresses {
+                if !peers.contains(address) {
+                    node.relay.forget_peer(address);
+                }
+            }
+            node.relay.node().set_peers(peers);
+        }
+    }
+
+    fn id_of(&self, address: &SocketAddr) -> usize {
+        (address.port() - SIM_BASE_PORT) as usize
+    }
+
+    /// Puts what nodes sent on the wire, losing some and delaying the rest.
+    fn flush(&mut self) {
+        let sent: Vec<_> = self.outbox.lock().unwrap().drain(..).collect();
+
+        for (from, to, message) in sent {
+            if self.rng.chance(self.config.loss) {
+                self.dropped += 1;
+                continue;
+            }
+
+            let spread = self
+                .config
+                .max_latency
+                .saturating_sub(self.config.min_latency)
+                + 1;
+            let latency = self.config.min_latency + self.rng.below(spread);
+            self.seq += 1;
+            self.queue.push(Envelope {
+                deliver_at: self.now() + latency,
+                seq: self.seq,
+                from,
+                to,
+                message,
+            });
+        }
+    }
+
+    /// Mines a block at `id` with a coinbase and its whole mempool, and announces it.
+    pub async fn mine(&mut self, id: usize) -> Result<Block> {
+        let now = self.now();
+        let node = &mut self.nodes[id];
+        let coinbase = Transaction::new(now, format!("mined by node {}", id).into_bytes());
+        let mut transactions = vec![coinbase];
+        transactions.extend(node.relay.mempool().lock().unwrap().transactions());
+
+        let block = node
+            .chain
+            .mine_next_block(now / 1000, encode_transactions(&transactions))
+            .await?;
+        Simulation::included(node, &block);
+        node.relay.broadcast_block(&block);
+        self.flush();
+
+        Ok(block)
+    }
+
+    pub fn submit(&mut self, id: usize, transaction: Transaction) {
+        self.nodes[id].relay.broadcast_tx(transaction);
+        self.flush();
+    }
+
+    fn included(node: &SimNode, block: &Block) {
+        node.relay.mempool().lock().unwrap().remove_included(block);
+    }
+
+    /// Delivers the next message, returns false when none is left.
+    pub async fn step(&mut self) -> Result<bool> {
+        let envelope = match self.queue.pop() {
+            Some(envelope) => envelope,
+            None => return Ok(false),
+        };
+        self.clock.set(self.now().max(envelope.deliver_at));
+
+        let (from, to) = (self.id_of(&envelope.from), self.id_of(&envelope.to));
+        if self.groups[from] != self.groups[to] {
+            self.dropped += 1;
+            return Ok(true);
+        }
+
+        self.delivered += 1;
+        self.deliver(to, envelope.from, envelope.message).await?;
+        self.flush();
+        Ok(true)
+    }
+
+    async fn deliver(&mut self, id: usize, from: SocketAddr, message: Message) -> Result<()> {
+        let node = &mut self.nodes[id];
+        let block = match node.relay.handle(&from, message).await {
+            Some(Received::Block(block)) => block,
+            _ => return Ok(()),
+        };
+
+        match node.chain.receive_block(&block).await? {
+            BlockStatus::Extended | BlockStatus::Reorganized => {
+                Simulation::included(node, &block);
+                node.relay.broadcast_block(&block);
+
+                let tip = node.chain.get_last_block().await?;
+                if tip.get_hash() != block.get_hash() {
+                    node.relay.broadcast_block(&tip);
+                }
+            }
+            BlockStatus::Orphan => {
+                let parent = InvItem::Block(block.get_prev_hash());
+                let _ = node
+                    .relay
+                    .node()
+                    .send(&from, Message::GetData(vec![parent]));
+            }
+            BlockStatus::Invalid => {
+                node.relay
+                    .node()
+                    .misbehaving(&from, Misbehavior::InvalidBlock);
+            }
+            BlockStatus::SideChain | BlockStatus::Known => {}
+        }
+
+        Ok(())
+    }
+
+    /// Delivers messages until there are none left.
+    pub async fn run_until_idle(&mut self) -> Result<()> {
+        while self.step().await? {}
+        Ok(())
+    }
+
+    /// Delivers what arrives in the next `millis` milliseconds.
+    pub async fn run_for(&mut self, millis: u64) -> Result<()> {
+        let until = self.now() + millis;
+
+        while self
+            .queue
+            .peek()
+            .is_some_and(|envelope| envelope.deliver_at <= until)
+        {
+            self.step().await?;
+        }
+
+        self.clock.set(until);
+        Ok(())
+    }
+
+    /// Whether every node has the same tip.
+    pub fn converged(&self) -> bool {
+        self.nodes
+            .iter()
+            .all(|node| node.tip() == self.nodes[0].tip())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::simulation::*;
+
+    const BLOCK_INTERVAL: u64 = 10_000;
+
+    async fn simulation(seed: u64, nodes: usize, loss: f64) -> Simulation {
+        Simulation::new(SimConfig {
+            seed,
+            nodes,
+            loss,
+            ..SimConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn rng_is_deterministic_test() {
+        let mut first = SimRng::new(7);
+        let mut second = SimRng::new(7);
+        let mut other = SimRng::new(8);
+
+        let draws: Vec<u64> = (0..10).map(|_| first.next_u64()).collect();
+        assert_eq!(
+            draws,
+            (0..10).map(|_| second.next_u64()).collect::<Vec<_>>()
+        );
+        assert_ne!(draws, (0..10).map(|_| other.next_u64()).collect::<Vec<_>>());
+        assert!(!first.chance(0.0));
+        assert!(first.chance(1.0));
+    }
+
+    #[tokio::test]
+    async fn propagation_test() -> Result<()> {
+        let mut sim = simulation(1, 5, 0.0).await;
+
+        for _ in 0..3 {
+            sim.advance(BLOCK_INTERVAL);
+            sim.mine(0).await?;
+            sim.run_until_idle().await?;
+        }
+
+        assert!(sim.converged());
+        assert!(sim.nodes().iter().all(|node| node.height() == 3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partition_fork_resolution_test() -> Result<()> {
+        let mut sim = simulation(2, 5, 0.0).await;
+        sim.partition(&[&[0, 1], &[2, 3, 4]]);
+
+        for round in 0..3 {
+            sim.advance(BLOCK_INTERVAL);
+            if round < 2 {
+                sim.mine(0).await?;
+            }
+            sim.mine(2).await?;
+            sim.run_until_idle().await?;
+        }
+
+        assert_eq!(sim.node(1).tip(), sim.node(0).tip());
+        assert_eq!(sim.node(4).tip(), sim.node(2).tip());
+        assert_ne!(sim.node(0).tip(), sim.node(2).tip());
+
+        let winner = sim.node(2).tip();
+        sim.heal().await?;
+        sim.run_until_idle().await?;
+
+        assert!(sim.converged());
+        assert_eq!(sim.node(0).tip(), winner);
+        assert_eq!(sim.node(0).height(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn late_node_syncs_test() -> Result<()> {
+        let mut sim = simulation(3, 4, 0.0).await;
+        sim.partition(&[&[0, 1, 2]]);
+
+        for _ in 0..5 {
+            sim.advance(BLOCK_INTERVAL);
+            sim.mine(1).await?;
+            sim.run_until_idle().await?;
+        }
+        assert_eq!(sim.node(3).height(), 0);
+
+        sim.heal().await?;
+        sim.run_until_idle().await?;
+
+        assert!(sim.converged());
+        assert_eq!(sim.node(3).height(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transactions_get_mined_test() -> Result<()> {
+        let mut sim = simulation(4, 3, 0.0).await;
+        let transaction = Transaction::new(1, b"pay".to_vec());
+
+        sim.submit(2, transaction.clone());
+        sim.run_until_idle().await?;
+        assert!(sim.nodes().iter().all(|node| node.mempool_len() == 1));
+
+        sim.advance(BLOCK_INTERVAL);
+        let block = sim.mine(0).await?;
+        sim.run_until_idle().await?;
+
+        assert!(block.transactions().unwrap().contains(&transaction));
+        assert!(sim.converged());
+        assert!(sim.nodes().iter().all(|node| node.mempool_len() == 0));
+        Ok(())
+    }
+
+    async fn lossy_run(seed: u64) -> Result<(Vec<BlockHash>, u64, u64, u64)> {
+        let mut sim = simulation(seed, 6, 0.2).await;
+
+        for round in 0..6 {
+            sim.advance(BLOCK_INTERVAL);
+            sim.mine(round % 3).await?;
+            sim.run_for(500).await?;
+        }
+        sim.run_until_idle().await?;
+
+        let tips = sim.nodes().iter().map(SimNode::tip).collect();
+        Ok((tips, sim.delivered, sim.dropped, sim.now()))
+    }
+
+    #[tokio::test]
+    async fn same_seed_same_run_test() -> Result<()> {
+        let first = lossy_run(42).await?;
+
+        assert_eq!(first, lossy_run(42).await?);
+        assert!(first.2 > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{bail, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::network::message::Message;
+use crate::network::peer::Misbehavior;
+use crate::network::transport::Transport;
+
+/// Messages sent during a simulation step, as `(from, to, message)`.
+pub type Outbox = Arc<Mutex<Vec<(SocketAddr, SocketAddr, Message)>>>;
+
+/// Simulated time in milliseconds, shared by the `Simulation` and its
+/// transports.
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Arc<AtomicU64>);
+
+impl Clock {
+    pub fn millis(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, millis: u64) {
+        self.0.store(millis, Ordering::SeqCst);
+    }
+}
+
+/// In-memory transport: sending only queues the message, the `Simulation`
+/// decides when, and whether, it gets delivered.
+#[derive(Clone)]
+pub struct SimTransport {
+    address: SocketAddr,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    outbox: Outbox,
+    misbehaving: Arc<Mutex<Vec<(SocketAddr, Misbehavior)>>>,
+    clock: Clock,
+}
+
+impl SimTransport {
+    pub fn new(address: SocketAddr, outbox: Outbox, clock: Clock) -> Self {
+        SimTransport {
+            address,
+            peers: Arc::new(Mutex::new(vec![])),
+            outbox,
+            misbehaving: Arc::new(Mutex::new(vec![])),
+            clock,
+        }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn set_peers(&self, mut peers: Vec<SocketAddr>) {
+        peers.sort();
+        *self.peers.lock().unwrap() = peers;
+    }
+
+    /// Every misbehavior reported so far.
+    pub fn reported(&self) -> Vec<(SocketAddr, Misbehavior)> {
+        self.misbehaving.lock().unwrap().clone()
+    }
+}
+
+impl Transport for SimTransport {
+    fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    fn send(&self, address: &SocketAddr, message: Message) -> Result<()> {
+        if !self.peers.lock().unwrap().contains(address) {
+            bail!("not connected to {}", address);
+        }
+
+        self.outbox
+            .lock()
+            .unwrap()
+            .push((self.address, *address, message));
+        Ok(())
+    }
+
+    fn misbehaving(&self, address: &SocketAddr, kind: Misbehavior) -> bool {
+        self.misbehaving.lock().unwrap().push((*address, kind));
+        false
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.millis() / 1000
+    }
+}
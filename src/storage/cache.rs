@@ -0,0 +1,228 @@
+use rocket::serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+
+/// Entries of each kind a client keeps by default.
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Up to `capacity` entries, dropping the least recently used first.
+struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    /// Last use -> key, oldest first.
+    uses: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            uses: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        self.uses.remove(used);
+        self.uses.insert(self.tick, key.clone());
+        *used = self.tick;
+
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.uses.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.uses.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.uses.remove(&used);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.uses.clear();
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Hit and miss counts since the client was opened, and what is cached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub blocks: usize,
+    pub headers: usize,
+    pub numbers: usize,
+    /// Most entries of each kind.
+    pub capacity: usize,
+}
+
+/// Recently used blocks and headers by hash, and main chain hashes by
+/// number. Blocks and headers never change under their hash; the number
+/// entries are kept right by the writes of the client owning the cache,
+/// which overwrites or drops them when a reorg moves the index.
+pub struct BlockCache {
+    blocks: Lru<BlockHash, Block>,
+    headers: Lru<BlockHash, BlockHeader>,
+    numbers: Lru<usize, BlockHash>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    /// Keeps up to `capacity` of each kind, nothing when 0.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            blocks: Lru::new(capacity),
+            headers: Lru::new(capacity),
+            numbers: Lru::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn count<T>(&mut self, found: Option<T>) -> Option<T> {
+        match found.is_some() {
+            true => self.hits += 1,
+            false => self.misses += 1,
+        }
+        found
+    }
+
+    pub fn block(&mut self, hash: &BlockHash) -> Option<Block> {
+        let block = self.blocks.get(hash);
+        self.count(block)
+    }
+
+    /// The header, or the header of the cached block.
+    pub fn header(&mut self, hash: &BlockHash) -> Option<BlockHeader> {
+        let header = match self.headers.get(hash) {
+            Some(header) => Some(header),
+            None => self.blocks.get(hash).map(|block| block.header()),
+        };
+        self.count(header)
+    }
+
+    pub fn hash(&mut self, number: usize) -> Option<BlockHash> {
+        let hash = self.numbers.get(&number);
+        self.count(hash)
+    }
+
+    pub fn insert_block(&mut self, block: &Block) {
+        self.headers.insert(block.get_hash(), block.header());
+        self.blocks.insert(block.get_hash(), block.clone());
+    }
+
+    pub fn insert_header(&mut self, header: &BlockHeader) {
+        self.headers.insert(header.hash, *header);
+    }
+
+    pub fn insert_number(&mut self, number: usize, hash: BlockHash) {
+        self.numbers.insert(number, hash);
+    }
+
+    /// Forgets the body, the header stays valid.
+    pub fn remove_block(&mut self, hash: &BlockHash) {
+        self.blocks.remove(hash);
+    }
+
+    pub fn remove_header(&mut self, hash: &BlockHash) {
+        self.blocks.remove(hash);
+        self.headers.remove(hash);
+    }
+
+    pub fn remove_number(&mut self, number: usize) {
+        self.numbers.remove(&number);
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.headers.clear();
+        self.numbers.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            blocks: self.blocks.len(),
+            headers: self.headers.len(),
+            numbers: self.numbers.len(),
+            capacity: self.blocks.capacity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::storage::cache::*;
+
+    #[test]
+    fn lru_test() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "one");
+        lru.insert(2, "two");
+        assert_eq!(lru.get(&1), Some("one"));
+
+        lru.insert(3, "three");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some("one"));
+        assert_eq!(lru.len(), 2);
+
+        lru.insert(1, "uno");
+        assert_eq!(lru.get(&1), Some("uno"));
+        assert_eq!(lru.len(), 2);
+
+        let mut disabled = Lru::new(0);
+        disabled.insert(1, "one");
+        assert_eq!(disabled.get(&1), None);
+    }
+
+    #[test]
+    fn block_cache_test() {
+        let mut cache = BlockCache::new(8);
+        let block = Block::default();
+
+        assert!(cache.block(&block.get_hash()).is_none());
+        cache.insert_block(&block);
+        cache.insert_number(1, block.get_hash());
+        assert_eq!(cache.block(&block.get_hash()), Some(block.clone()));
+        assert_eq!(cache.header(&block.get_hash()), Some(block.header()));
+
+        cache.remove_block(&block.get_hash());
+        assert!(cache.block(&block.get_hash()).is_none());
+        assert_eq!(cache.header(&block.get_hash()), Some(block.header()));
+        cache.remove_number(1);
+        assert!(cache.hash(1).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 3));
+        assert_eq!((stats.blocks, stats.headers, stats.numbers), (0, 1, 0));
+    }
+}
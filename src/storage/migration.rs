@@ -0,0 +1,209 @@
+use anyhow::{bail, Result};
+
+use crate::storage::Client;
+
+/// Every step of the key layout in order, the schema version of a dataset
+/// is how many of them it went through.
+pub const MIGRATIONS: [Migration; 2] = [Migration::BlockHashKey, Migration::NodeCount];
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+/// Where every node in the database kept its count before `NodeCount`.
+pub const LEGACY_COUNT_KEY: &str = "block_count";
+
+/// One change of the key layout, applied to the data of one node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Migration {
+    /// Number index keys move from `{node}::bock_hash::0x..` to
+    /// `{node}::block_hash::0x..`.
+    BlockHashKey,
+    /// The count moves from `block_count`, shared by every node in the
+    /// database, to `{node}::block_count`.
+    NodeCount,
+}
+
+impl Migration {
+    /// The schema version a dataset is at after this step.
+    pub fn version(&self) -> usize {
+        match self {
+            Migration::BlockHashKey => 1,
+            Migration::NodeCount => 2,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Migration::BlockHashKey => "rename bock_hash keys to block_hash",
+            Migration::NodeCount => "keep the block count per node",
+        }
+    }
+
+    async fn run(&self, client: &mut Client) -> Result<()> {
+        match self {
+            Migration::BlockHashKey => {
+                let legacy = client.namespace.key("bock_hash::");
+                let current = client.namespace.key("block_hash::");
+                for key in client.keys(&legacy).await? {
+                    let renamed = format!("{}{}", current, &key[legacy.len()..]);
+                    client.rename(&key, &renamed).await?;
+                }
+            }
+            // the shared count is whichever node saved a block last, the
+            // node's own number index tells where its chain ends, so no
+            // node needs it and the first one to migrate drops it
+            Migration::NodeCount => {
+                if let Some(tip) = client.get_indexed_numbers().await?.last() {
+                    client.set_block_count(*tip).await?;
+                }
+                client.del_data(LEGACY_COUNT_KEY.to_string()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Brings the data of the client's node up to `SCHEMA_VERSION`, returning
+/// the steps it took. The version is stored after each step, so a run cut
+/// short picks up where it stopped. Data written by a newer build is left
+/// alone and is an error.
+pub async fn migrate(client: &mut Client) -> Result<Vec<Migration>> {
+    let version = client.get_schema_version().await?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "node {} storage is at schema {}, this build knows up to {}",
+            client.node_id(),
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    let steps = MIGRATIONS[version..].to_vec();
+    for migration in &steps {
+        migration.run(client).await?;
+        client.set_schema_version(migration.version()).await?;
+        println!(
+            "NODE {} STORAGE MIGRATED TO SCHEMA {}: {}",
+            client.node_id(),
+            migration.version(),
+            migration.description()
+        );
+    }
+
+    Ok(steps)
+}
+
+/// Fails unless the data of the client's node is at `SCHEMA_VERSION`,
+/// writing nothing, for clients on data another node owns and migrates. A
+/// node without data yet passes.
+pub async fn check(client: &mut Client) -> Result<()> {
+    let version = client.get_schema_version().await?;
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+    if version > SCHEMA_VERSION {
+        bail!(
+            "node {} storage is at schema {}, this build knows up to {}",
+            client.node_id(),
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    let prefix = client.namespace.prefix();
+    if !client.keys(&prefix).await?.is_empty() {
+        bail!(
+            "node {} storage is at schema {}, start that node to migrate it to {}",
+            client.node_id(),
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::blockchain::chain::Chain;
+    use crate::storage::migration::*;
+    use crate::storage::MemoryStore;
+
+    /// `node` mined `blocks` blocks, then its keys put back as they were
+    /// before versioning.
+    async fn legacy(store: &MemoryStore, node: &str, blocks: u64) -> Result<Chain> {
+        let client = Client::memory(node.to_string(), store.clone());
+        let mut chain = Chain::with_client(client).await?;
+        for timestamp in 1..=blocks {
+            chain.mine_next_block(timestamp * 10, vec![]).await?;
+        }
+
+        let mut keys = store.0.lock().unwrap();
+        let node_keys: Vec<String> = keys
+            .keys()
+            .filter(|key| key.starts_with(&format!("{}::", node)))
+            .cloned()
+            .collect();
+        for key in node_keys {
+            let value = keys.remove(&key).unwrap();
+            let legacy = match key.split_once("::block_hash::") {
+                Some((node, number)) => format!("{}::bock_hash::{}", node, number),
+                None if key.ends_with("::block_count") => LEGACY_COUNT_KEY.to_string(),
+                None => key,
+            };
+            keys.insert(legacy, value);
+        }
+
+        Ok(chain)
+    }
+
+    #[tokio::test]
+    async fn migrate_test() -> Result<()> {
+        let store = MemoryStore::default();
+        let mut first = legacy(&store, "0", 3).await?;
+        legacy(&store, "1", 1).await?;
+
+        let mut client = Client::memory("0".to_string(), store.clone());
+        assert_eq!(client.get_block_count().await, 0);
+        assert!(store.0.lock().unwrap().contains_key(LEGACY_COUNT_KEY));
+        assert_eq!(migrate(&mut client).await?, MIGRATIONS.to_vec());
+        assert!(!store.0.lock().unwrap().contains_key(LEGACY_COUNT_KEY));
+        assert_eq!(client.get_schema_version().await?, SCHEMA_VERSION);
+        assert!(migrate(&mut client).await?.is_empty());
+
+        let migrated = Chain::with_client(client.clone()).await?;
+        assert_eq!(migrated.hashes, first.hashes);
+        assert_eq!(
+            client.get_block_by_number(3).await?,
+            first.get_block_by_chain_index(3).await?
+        );
+
+        let mut other = Client::memory("1".to_string(), store);
+        migrate(&mut other).await?;
+        assert_eq!(other.get_block_count().await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn check_test() -> Result<()> {
+        let store = MemoryStore::default();
+        let mut client = Client::memory("0".to_string(), store.clone()).view("0");
+        check(&mut client).await?;
+
+        legacy(&store, "0", 2).await?;
+        let legacy_keys = store.0.lock().unwrap().clone();
+        assert!(check(&mut client).await.is_err());
+        assert_eq!(*store.0.lock().unwrap(), legacy_keys);
+
+        migrate(&mut Client::memory("0".to_string(), store)).await?;
+        check(&mut client).await
+    }
+
+    #[tokio::test]
+    async fn newer_schema_test() -> Result<()> {
+        let mut client = Client::memory("0".to_string(), MemoryStore::default());
+        client.set_schema_version(SCHEMA_VERSION + 1).await?;
+
+        assert!(migrate(&mut client).await.is_err());
+        assert!(check(&mut client).await.is_err());
+        assert_eq!(client.get_schema_version().await?, SCHEMA_VERSION + 1);
+        Ok(())
+    }
+}
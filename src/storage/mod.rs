@@ -1,165 +1,738 @@
-use anyhow::Result;
-use hex::{decode, encode};
-use redis::{from_redis_value, Client as RedisClient, Connection, JsonCommands, Value};
-use rocket::serde::json::from_str;
+pub mod cache;
+pub mod migration;
 
-use crate::blockchain::block::{Block, BlockHash};
+use anyhow::{anyhow, Result};
+use hex::{decode, encode};
+use redis::aio::ConnectionManager;
+use redis::{
+    cmd, from_redis_value, AsyncCommands, Client as RedisClient, JsonAsyncCommands, Value,
+};
+use rocket::serde::json::{from_str, serde_json::to_string};
+use rocket::serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::blockchain::block::{Block, BlockHash, BlockHeader};
+use crate::blockchain::transaction::TxHash;
+use crate::config::NodeConfig;
+use crate::network::peer::PeerAddress;
+use crate::storage::cache::{BlockCache, CacheStats, DEFAULT_CACHE_SIZE};
 
 pub static DB_ENDPOINT: &str = "redis://127.0.0.1:6379";
-pub static COUNT_KEY: &str = "block_count";
 
-fn get_count_key() -> String {
-    COUNT_KEY.to_string()
+/// Returned when a key is not in the store, so callers can tell a missing
+/// block from a broken connection.
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl fmt::Display for NotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "key {} not found", self.0)
+    }
 }
 
-pub struct Client {
-    pub connection_instance: Connection,
-    node_id: String,
+impl std::error::Error for NotFound {}
+
+/// Returned for main chain blocks whose body a pruned node deleted, only
+/// their header is kept.
+#[derive(Debug)]
+pub struct Pruned(pub usize);
+
+impl fmt::Display for Pruned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} is pruned, only its header is kept", self.0)
+    }
 }
 
-impl Default for Client {
-    fn default() -> Self {
-        let node_id = dotenv::var("NODE_ID").unwrap_or("0".to_string());
-        Client::new(node_id).unwrap()
+impl std::error::Error for Pruned {}
+
+/// Returned for writes through a read-only view of a namespace.
+#[derive(Debug)]
+pub struct ReadOnly(pub String);
+
+impl fmt::Display for ReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "namespace {} is read-only here", self.0)
+    }
+}
+
+impl std::error::Error for ReadOnly {}
+
+/// The prefix of every key of one node, `{name}::`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace(String);
+
+impl Namespace {
+    pub fn node(node_id: &str) -> Self {
+        Namespace(node_id.to_string())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    pub fn prefix(&self) -> String {
+        format!("{}::", self.0)
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}::{}", self.0, key)
+    }
+
+    /// The namespace `key` belongs to, `None` for keys outside any.
+    fn of(key: &str) -> Option<Namespace> {
+        key.split_once("::")
+            .map(|(name, _)| Namespace(name.to_string()))
     }
 }
 
+/// Keys and JSON values kept in process, shared by every `Client` opened on
+/// it. Stands in for Redis in tests and simulations.
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<HashMap<String, String>>>);
+
+#[derive(Clone)]
+enum Backend {
+    Redis(ConnectionManager),
+    Memory(MemoryStore),
+}
+
+/// Storage handle for one node, every key it touches is in the node's
+/// namespace. Clones share the connection, which reconnects on its own, so
+/// one client can serve every task, and the block cache.
+#[derive(Clone)]
+pub struct Client {
+    backend: Backend,
+    namespace: Namespace,
+    read_only: bool,
+    cache: Arc<Mutex<BlockCache>>,
+}
+
 impl Client {
-    pub fn new(node_id: String) -> Result<Client> {
-        let client = RedisClient::open(DB_ENDPOINT)?;
-        let connection_instance = client.get_connection()?;
+    /// Connects to the configured Redis as the configured node.
+    pub async fn from_config(config: &NodeConfig) -> Result<Client> {
+        Ok(Client::new(config.node_id.clone(), &config.db)
+            .await?
+            .with_cache(config.cache_size))
+    }
+
+    /// Connects to Redis as `node_id`, upgrading the node's data to the
+    /// current schema first.
+    pub async fn new(node_id: String, db: &str) -> Result<Client> {
+        let mut client = Client::connect(node_id, db, false).await?;
+        migration::migrate(&mut client).await?;
+
+        Ok(client)
+    }
+
+    /// Connects to Redis with a read-only view on the namespace of
+    /// `node_id`, writing nothing, not even a migration.
+    pub async fn open_view(node_id: String, db: &str) -> Result<Client> {
+        Client::connect(node_id, db, true).await
+    }
+
+    async fn connect(node_id: String, db: &str, read_only: bool) -> Result<Client> {
+        let client = RedisClient::open(db)?;
+        let connection_instance = ConnectionManager::new(client).await?;
 
         Ok(Client {
-            connection_instance,
-            node_id,
+            backend: Backend::Redis(connection_instance),
+            namespace: Namespace::node(&node_id),
+            read_only,
+            cache: Arc::new(Mutex::new(BlockCache::new(DEFAULT_CACHE_SIZE))),
         })
     }
 
-    fn get_node_id(&self) -> String {
-        self.node_id.clone()
+    pub fn memory(node_id: String, store: MemoryStore) -> Client {
+        Client {
+            backend: Backend::Memory(store),
+            namespace: Namespace::node(&node_id),
+            read_only: false,
+            cache: Arc::new(Mutex::new(BlockCache::new(DEFAULT_CACHE_SIZE))),
+        }
+    }
+
+    /// The client with a new cache of up to `capacity` blocks, headers and
+    /// hashes each, none when 0.
+    pub fn with_cache(mut self, capacity: usize) -> Client {
+        self.cache = Arc::new(Mutex::new(BlockCache::new(capacity)));
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
+    }
+
+    /// A read-only client on the namespace of `node_id`, sharing this
+    /// client's connection but not its cache.
+    pub fn view(&self, node_id: &str) -> Client {
+        let capacity = self.cache_stats().capacity;
+        Client {
+            backend: self.backend.clone(),
+            namespace: Namespace::node(node_id),
+            read_only: true,
+            cache: Arc::new(Mutex::new(BlockCache::new(capacity))),
+        }
+    }
+
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
     }
 
-    fn block_key(&self, hash: &BlockHash) -> Result<String> {
-        let mut prefix = self.get_node_id();
-        let vec_hash = &encode(hash);
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn block_key(&self, hash: &BlockHash) -> String {
+        self.namespace.key(&format!("block::0x{}", encode(hash)))
+    }
+
+    fn header_key(&self, hash: &BlockHash) -> String {
+        self.namespace.key(&format!("header::0x{}", encode(hash)))
+    }
+
+    fn hash_key(&self, number: usize) -> String {
+        self.namespace
+            .key(&format!("block_hash::0x{}", encode(number.to_be_bytes())))
+    }
+
+    fn tx_key(&self, hash: &TxHash) -> String {
+        self.namespace.key(&format!("tx::0x{}", encode(hash)))
+    }
+
+    fn pruned_key(&self) -> String {
+        self.namespace.key("pruned")
+    }
+
+    fn count_key(&self) -> String {
+        self.namespace.key("block_count")
+    }
+
+    fn schema_key(&self) -> String {
+        self.namespace.key("schema")
+    }
+
+    fn peers_key(&self) -> String {
+        self.namespace.key("peers")
+    }
+
+    /// Refuses writes through a read-only view.
+    fn writable(&self) -> Result<()> {
+        match self.read_only {
+            true => Err(ReadOnly(self.node_id()).into()),
+            false => Ok(()),
+        }
+    }
+
+    async fn get_data(&mut self, key: &String) -> Result<String> {
+        match &mut self.backend {
+            Backend::Redis(connection) => {
+                let res: Value = connection.json_get(key, ".").await?;
+                if res == Value::Nil {
+                    return Err(NotFound(key.clone()).into());
+                }
+                let str_value: String = from_redis_value(&res)?;
+                Ok(str_value)
+            }
+            Backend::Memory(store) => store
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .ok_or_else(|| NotFound(key.clone()).into()),
+        }
+    }
 
-        prefix.push_str("::block::0x");
-        prefix.push_str(vec_hash);
-        Ok(prefix)
+    async fn set_data<T: Serialize + Send + Sync>(&mut self, key: String, value: &T) -> Result<()> {
+        self.writable()?;
+        match &mut self.backend {
+            Backend::Redis(connection) => {
+                connection.json_set::<_, _, _, ()>(key, ".", value).await?
+            }
+            Backend::Memory(store) => {
+                store.0.lock().unwrap().insert(key, to_string(value)?);
+            }
+        }
+
+        Ok(())
     }
 
-    fn hash_key(&self, number: usize) -> Result<String> {
-        let mut prefix = self.get_node_id();
-        let vec_number = &encode(number.to_be_bytes());
+    async fn del_data(&mut self, key: String) -> Result<()> {
+        self.writable()?;
+        match &mut self.backend {
+            Backend::Redis(connection) => connection.json_del::<_, _, ()>(key, ".").await?,
+            Backend::Memory(store) => {
+                store.0.lock().unwrap().remove(&key);
+            }
+        }
+
+        Ok(())
+    }
 
-        prefix.push_str("::bock_hash::0x");
-        prefix.push_str(vec_number);
-        Ok(prefix)
+    /// Every key starting with `prefix`.
+    async fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        match &mut self.backend {
+            Backend::Redis(connection) => {
+                let pattern = format!("{}*", escape_pattern(prefix));
+                let mut iter = connection.scan_match::<_, String>(pattern).await?;
+                let mut keys = vec![];
+                while let Some(key) = iter.next_item().await {
+                    keys.push(key);
+                }
+                Ok(keys)
+            }
+            Backend::Memory(store) => Ok(store
+                .0
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect()),
+        }
     }
 
-    fn get_data(&mut self, key: &String) -> Result<String> {
-        let res: Value = self.connection_instance.json_get(key, ".")?;
-        let str_value: String = from_redis_value(&res)?;
-        Ok(str_value)
+    /// Moves the value at `from` to `to`, replacing what was there.
+    async fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.writable()?;
+        match &mut self.backend {
+            Backend::Redis(connection) => {
+                cmd("RENAME")
+                    .arg(from)
+                    .arg(to)
+                    .query_async::<_, ()>(connection)
+                    .await?
+            }
+            Backend::Memory(store) => {
+                let mut store = store.0.lock().unwrap();
+                let value = store
+                    .remove(from)
+                    .ok_or_else(|| NotFound(from.to_string()))?;
+                store.insert(to.to_string(), value);
+            }
+        }
+        self.cache.lock().unwrap().clear();
+
+        Ok(())
     }
 
-    pub fn get_block_count(&mut self) -> usize {
-        let count_str = Client::get_data(self, &get_count_key()).unwrap_or("0".to_string());
+    /// Layout version of the node's data, 0 for data older than versioning.
+    pub async fn get_schema_version(&mut self) -> Result<usize> {
+        match self.get_data(&self.schema_key()).await {
+            Ok(raw) => Ok(raw.parse()?),
+            Err(err) if err.is::<NotFound>() => Ok(0),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn set_schema_version(&mut self, version: usize) -> Result<()> {
+        self.set_data(self.schema_key(), &version).await
+    }
+
+    pub async fn get_block_count(&mut self) -> usize {
+        let count_str = self
+            .get_data(&self.count_key())
+            .await
+            .unwrap_or("0".to_string());
         count_str.parse().unwrap_or(0)
     }
 
-    pub fn get_block_by_str(&mut self, block_hash: &String) -> Result<Block> {
-        let hash = decode(block_hash).unwrap();
-        self.get_block_by_vec(&hash)
+    pub async fn get_block_by_str(&mut self, block_hash: &str) -> Result<Block> {
+        let hash = decode(block_hash)?;
+        self.get_block_by_vec(&hash).await
     }
 
-    pub fn get_block_by_vec(&mut self, block_hash: &Vec<u8>) -> Result<Block> {
-        let hash: &[u8; 32] = &block_hash[..32].try_into().unwrap();
-        self.get_block_by_hash(hash)
+    pub async fn get_block_by_vec(&mut self, block_hash: &[u8]) -> Result<Block> {
+        let hash: BlockHash = block_hash
+            .try_into()
+            .map_err(|_| anyhow!("block hash must be 32 bytes, got {}", block_hash.len()))?;
+        self.get_block_by_hash(&hash).await
     }
 
-    pub fn get_block_by_hash(&mut self, block_hash: &BlockHash) -> Result<Block> {
-        let hash_key = &self.block_key(block_hash)?;
-        let raw_block = self.get_data(hash_key)?;
+    pub async fn get_block_by_hash(&mut self, block_hash: &BlockHash) -> Result<Block> {
+        if let Some(block) = self.cache.lock().unwrap().block(block_hash) {
+            return Ok(block);
+        }
+
+        let hash_key = &self.block_key(block_hash);
+        let raw_block = match self.get_data(hash_key).await {
+            Ok(raw_block) => raw_block,
+            Err(err) if err.is::<NotFound>() => {
+                return Err(self.missing_body(block_hash, err).await)
+            }
+            Err(err) => return Err(err),
+        };
         let block: Block = from_str(&raw_block)?;
+        self.cache.lock().unwrap().insert_block(&block);
 
         Ok(block)
     }
 
-    pub fn get_block_by_number(&mut self, block_number: usize) -> Result<Block> {
-        let num_key = &self.hash_key(block_number)?;
-        let raw_hash = self.get_data(num_key)?;
+    /// `Pruned` when the header says the body was pruned, `err` otherwise.
+    async fn missing_body(&mut self, block_hash: &BlockHash, err: anyhow::Error) -> anyhow::Error {
+        let header = self.get_data(&self.header_key(block_hash)).await;
+        let number = match header.map(|raw| from_str::<BlockHeader>(&raw)) {
+            Ok(Ok(header)) => header.block_number,
+            _ => return err,
+        };
+
+        match 0 < number && number < self.get_pruned_height().await {
+            true => Pruned(number).into(),
+            false => err,
+        }
+    }
+
+    /// Main chain hash at `block_number`, from the number index.
+    pub async fn get_hash_by_number(&mut self, block_number: usize) -> Result<BlockHash> {
+        if let Some(hash) = self.cache.lock().unwrap().hash(block_number) {
+            return Ok(hash);
+        }
+
+        let num_key = &self.hash_key(block_number);
+        let raw_hash = self.get_data(num_key).await?;
         let hash = from_str(&raw_hash)?;
+        self.cache.lock().unwrap().insert_number(block_number, hash);
 
-        let hash_key = self.block_key(&hash)?;
-        let raw_block = self.get_data(&hash_key)?;
-        let block: Block = from_str(&raw_block)?;
+        Ok(hash)
+    }
 
-        Ok(block)
+    /// Every number with an entry in the number index, in order, whether
+    /// or not the count reaches it.
+    pub async fn get_indexed_numbers(&mut self) -> Result<Vec<usize>> {
+        let prefix = self.namespace.key("block_hash::0x");
+        let mut numbers: Vec<usize> = self
+            .keys(&prefix)
+            .await?
+            .iter()
+            .filter_map(|key| {
+                let bytes = decode(&key[prefix.len()..]).ok()?.try_into().ok()?;
+                Some(usize::from_be_bytes(bytes))
+            })
+            .collect();
+
+        numbers.sort_unstable();
+        Ok(numbers)
+    }
+
+    pub async fn get_block_by_number(&mut self, block_number: usize) -> Result<Block> {
+        let hash = self.get_hash_by_number(block_number).await?;
+
+        self.get_block_by_hash(&hash).await
+    }
+
+    pub async fn get_header_by_hash(&mut self, block_hash: &BlockHash) -> Result<BlockHeader> {
+        if let Some(header) = self.cache.lock().unwrap().header(block_hash) {
+            return Ok(header);
+        }
+
+        let header_key = &self.header_key(block_hash);
+        match self.get_data(header_key).await {
+            Ok(raw_header) => {
+                let header = from_str(&raw_header)?;
+                self.cache.lock().unwrap().insert_header(&header);
+                Ok(header)
+            }
+            // blocks saved before headers were stored on their own
+            Err(_) => Ok(self.get_block_by_hash(block_hash).await?.header()),
+        }
+    }
+
+    pub async fn get_header_by_number(&mut self, block_number: usize) -> Result<BlockHeader> {
+        let hash = self.get_hash_by_number(block_number).await?;
+
+        self.get_header_by_hash(&hash).await
+    }
+
+    /// Headers for the numbers `from..=to`, stopping at the first missing one.
+    pub async fn get_headers(&mut self, from: usize, to: usize) -> Result<Vec<BlockHeader>> {
+        let mut headers = vec![];
+
+        for number in from..=to {
+            match self.get_header_by_number(number).await {
+                Ok(header) => headers.push(header),
+                Err(_) => break,
+            }
+        }
+
+        Ok(headers)
+    }
+
+    pub async fn get_last_block(&mut self) -> Result<Block> {
+        let last_block_number = self.get_block_count().await;
+        self.get_block_by_number(last_block_number).await
+    }
+
+    pub fn node_id(&self) -> String {
+        self.namespace.name().to_string()
+    }
+
+    /// Every namespace with a key in the store, sorted.
+    pub async fn list_namespaces(&mut self) -> Result<Vec<Namespace>> {
+        let mut namespaces: Vec<_> = self
+            .keys("")
+            .await?
+            .iter()
+            .filter_map(|key| Namespace::of(key))
+            .collect();
+        namespaces.sort_by(|a, b| a.name().cmp(b.name()));
+        namespaces.dedup();
+
+        Ok(namespaces)
+    }
+
+    /// Deletes every key of the client's namespace, returning how many.
+    pub async fn drop_namespace(&mut self) -> Result<usize> {
+        self.writable()?;
+        let keys = self.keys(&self.namespace.prefix()).await?;
+        for key in &keys {
+            self.del_data(key.clone()).await?;
+        }
+        self.cache.lock().unwrap().clear();
+
+        Ok(keys.len())
+    }
+
+    pub async fn save_block(&mut self, block: &Block) -> Result<bool> {
+        let block_key = self.block_key(&block.get_hash());
+        let header_key = self.header_key(&block.get_hash());
+        let hash_key = self.hash_key(block.get_block_number());
+
+        self.set_data(self.count_key(), &block.get_block_number())
+            .await?;
+        self.set_data(block_key, block).await?;
+        self.set_data(header_key, &block.header()).await?;
+        self.set_data(hash_key, &block.get_hash()).await?;
+
+        for transaction in block.transactions().unwrap_or_default() {
+            self.set_data(self.tx_key(&transaction.hash()), &block.get_hash())
+                .await?;
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert_block(block);
+        cache.insert_number(block.get_block_number(), block.get_hash());
+
+        Ok(true)
+    }
+
+    /// Stores a block off the main chain, without touching the number index
+    /// or the count.
+    pub async fn save_side_block(&mut self, block: &Block) -> Result<bool> {
+        let block_key = self.block_key(&block.get_hash());
+        let header_key = self.header_key(&block.get_hash());
+
+        self.set_data(block_key, block).await?;
+        self.set_data(header_key, &block.header()).await?;
+        self.cache.lock().unwrap().insert_block(block);
+
+        Ok(true)
+    }
+
+    /// Stores a main chain header and its number index without the body,
+    /// for chains started from a snapshot.
+    pub async fn save_header(&mut self, header: &BlockHeader) -> Result<bool> {
+        let header_key = self.header_key(&header.hash);
+        let hash_key = self.hash_key(header.block_number);
+
+        self.set_data(header_key, header).await?;
+        self.set_data(hash_key, &header.hash).await?;
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.insert_header(header);
+        cache.insert_number(header.block_number, header.hash);
+
+        Ok(true)
+    }
+
+    pub async fn index_tx(&mut self, tx_hash: &TxHash, block_hash: &BlockHash) -> Result<bool> {
+        self.set_data(self.tx_key(tx_hash), block_hash).await?;
+
+        Ok(true)
+    }
+
+    /// Deletes a block body, keeping its header and index entries.
+    pub async fn prune_block(&mut self, block_hash: &BlockHash) -> Result<bool> {
+        let block_key = self.block_key(block_hash);
+        self.del_data(block_key).await?;
+        self.cache.lock().unwrap().remove_block(block_hash);
+
+        Ok(true)
+    }
+
+    /// Bodies of the blocks from 1 up to this number are not stored, 0 when
+    /// every body is.
+    pub async fn get_pruned_height(&mut self) -> usize {
+        match self.get_data(&self.pruned_key()).await {
+            Ok(raw) => raw.parse().unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+
+    pub async fn set_pruned_height(&mut self, block_number: usize) -> Result<bool> {
+        self.set_data(self.pruned_key(), &block_number).await?;
+
+        Ok(true)
+    }
+
+    /// Makes `block_number` the tip number, used when repairing the index.
+    pub async fn set_block_count(&mut self, block_number: usize) -> Result<bool> {
+        self.set_data(self.count_key(), &block_number).await?;
+
+        Ok(true)
+    }
+
+    /// Drops the number -> hash entry, used when a reorg shortens the chain.
+    pub async fn unindex_block(&mut self, block_number: usize) -> Result<bool> {
+        let hash_key = self.hash_key(block_number);
+        self.del_data(hash_key).await?;
+        self.cache.lock().unwrap().remove_number(block_number);
+
+        Ok(true)
     }
 
-    pub fn get_last_block(&mut self) -> Result<Block> {
-        let last_block_number = self.get_block_count();
-        self.get_block_by_number(last_block_number)
+    /// Hash of the last main chain block the transaction was saved with.
+    pub async fn get_tx_block(&mut self, tx_hash: &TxHash) -> Result<BlockHash> {
+        let raw_hash = self.get_data(&self.tx_key(tx_hash)).await?;
+        let hash = from_str(&raw_hash)?;
+
+        Ok(hash)
     }
 
-    pub fn set_node_id(&mut self, id: String) {
-        self.node_id = id;
+    /// Whether the block is stored, or was and got pruned.
+    pub async fn has_block(&mut self, block_hash: &BlockHash) -> bool {
+        match self.get_block_by_hash(block_hash).await {
+            Ok(_) => true,
+            Err(err) => err.is::<Pruned>(),
+        }
     }
 
-    pub fn save_block(&mut self, block: &Block) -> Result<bool> {
-        let block_key = self.block_key(&block.get_hash())?;
-        let hash_key = self.hash_key(block.get_block_number())?;
+    pub async fn get_peers(&mut self) -> Result<Vec<PeerAddress>> {
+        let raw_peers = self.get_data(&self.peers_key()).await?;
+        let peers = from_str(&raw_peers)?;
+
+        Ok(peers)
+    }
 
-        self.connection_instance
-            .json_set(COUNT_KEY, ".", &block.get_block_number())?;
-        self.connection_instance.json_set(block_key, ".", block)?;
-        self.connection_instance
-            .json_set(hash_key, ".", &block.get_hash())?;
+    pub async fn save_peers(&mut self, peers: &[PeerAddress]) -> Result<bool> {
+        self.set_data(self.peers_key(), &peers).await?;
 
         Ok(true)
     }
 
-    pub fn delete_block(&mut self, block_hash: &BlockHash) -> Result<bool> {
-        let block = self.get_block_by_hash(block_hash)?;
-        let block_key = self.block_key(&block.get_hash())?;
-        let hash_key = self.hash_key(block.get_block_number())?;
+    pub async fn delete_block(&mut self, block_hash: &BlockHash) -> Result<bool> {
+        let block = self.get_block_by_hash(block_hash).await?;
+        let block_key = self.block_key(&block.get_hash());
+        let header_key = self.header_key(&block.get_hash());
+        let hash_key = self.hash_key(block.get_block_number());
+
+        self.del_data(block_key).await?;
+        self.del_data(header_key).await?;
+        self.del_data(hash_key).await?;
 
-        self.connection_instance.json_del(block_key, ".")?;
-        self.connection_instance.json_del(hash_key, ".")?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove_header(block_hash);
+        cache.remove_number(block.get_block_number());
         Ok(true)
     }
 }
 
+/// `prefix` with the glob characters of Redis patterns escaped.
+fn escape_pattern(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+#[cfg(test)]
 mod test {
     use crate::blockchain::block::*;
     use crate::storage::*;
 
-    #[allow(dead_code)]
-    fn remove(client: &mut Client, blocks: Vec<&Block>) -> Result<()> {
+    async fn remove(client: &mut Client, blocks: Vec<&Block>) -> Result<()> {
         for block in blocks.iter() {
-            client.delete_block(&block.get_hash())?;
+            client.delete_block(&block.get_hash()).await?;
         }
 
         Ok(())
     }
 
-    #[test]
-    #[allow(dead_code)]
-    fn save_and_get_block() -> Result<()> {
-        let mut db = Client::default()?;
+    async fn save_and_get(db: &mut Client) -> Result<()> {
         let block = Block::default();
 
-        db.save_block(&block)?;
+        db.save_block(&block).await?;
 
-        let block_by_hash = db.get_block_by_hash(&block.get_hash())?;
-        let block_by_number = db.get_block_by_number(block.get_block_number())?;
+        let block_by_hash = db.get_block_by_hash(&block.get_hash()).await?;
+        let block_by_number = db.get_block_by_number(block.get_block_number()).await?;
+        let header = db.get_header_by_number(block.get_block_number()).await?;
 
         assert!(block == block_by_hash, "block by hash is not equal");
         assert!(block == block_by_number, "block by number is not equal");
+        assert!(block.header() == header, "header is not equal");
 
-        remove(&mut db, [&block].to_vec())?;
+        remove(db, [&block].to_vec()).await?;
+        assert!(!db.has_block(&block.get_hash()).await);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a RedisJSON instance, see redis.sh"]
+    async fn save_and_get_block() -> Result<()> {
+        save_and_get(&mut Client::new("0".to_string(), DB_ENDPOINT).await?).await
+    }
+
+    #[tokio::test]
+    async fn save_and_get_block_in_memory() -> Result<()> {
+        let store = MemoryStore::default();
+        save_and_get(&mut Client::memory("0".to_string(), store.clone())).await?;
+
+        let mut db = Client::memory("1".to_string(), store.clone());
+        let mut other = Client::memory("2".to_string(), store);
+        db.save_block(&Block::default()).await?;
+
+        assert!(db.has_block(&Block::default().get_hash()).await);
+        assert!(!other.has_block(&Block::default().get_hash()).await);
+
+        let missing = other.get_block_by_number(1).await.unwrap_err();
+        assert!(missing.is::<NotFound>());
+        assert!(db.get_block_by_str("0abc").await.is_err());
+        Ok(())
+    }
 
+    #[tokio::test]
+    async fn namespace_test() -> Result<()> {
+        let store = MemoryStore::default();
+        let mut first = Client::memory("1".to_string(), store.clone());
+        let mut second = Client::memory("2".to_string(), store);
+        first.save_block(&Block::genesis()).await?;
+        second.save_block(&Block::default()).await?;
+        assert_eq!(first.get_block_count().await, 0);
+        assert_eq!(second.get_block_count().await, 1);
+
+        let mut view = first.view("2");
+        assert!(view.is_read_only());
+        assert_eq!(view.get_block_by_number(1).await?, Block::default());
+        let write = view.save_block(&Block::genesis()).await.unwrap_err();
+        assert!(write.is::<ReadOnly>());
+        assert!(view.drop_namespace().await.is_err());
+
+        let names = |namespaces: Vec<Namespace>| {
+            namespaces
+                .iter()
+                .map(|namespace| namespace.name().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(first.list_namespaces().await?), ["1", "2"]);
+        assert_eq!(second.drop_namespace().await?, 4);
+        assert_eq!(names(first.list_namespaces().await?), ["1"]);
+        assert!(first.has_block(&Block::genesis().get_hash()).await);
         Ok(())
     }
 }